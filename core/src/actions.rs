@@ -0,0 +1,86 @@
+/// Something a button can do: what the LCD shows while it runs and the payload
+/// published on the command topic
+pub struct ButtonAction {
+    /// Stable name used in JSON events
+    pub id: &'static str,
+    /// Short name used in the menu
+    pub name: &'static str,
+    pub line_1: &'static str,
+    pub line_2: &'static str,
+    pub payload: &'static str,
+}
+
+pub static ACTIONS: &[ButtonAction] = &[
+    ButtonAction {
+        id: "ac",
+        name: "AC",
+        line_1: "TURN ON/OFF AC",
+        line_2: "",
+        payload: "b",
+    },
+    ButtonAction {
+        id: "air_filter",
+        name: "AIR FILTER",
+        line_1: "TURN ON/OFF",
+        line_2: "   AIR FILTER",
+        payload: "c",
+    },
+    ButtonAction {
+        id: "light_day",
+        name: "LIGHT DAY",
+        line_1: "LIGHT MODE",
+        line_2: "   DAY",
+        payload: "d",
+    },
+    ButtonAction {
+        id: "light_night",
+        name: "LIGHT NIGHT",
+        line_1: "LIGHT MODE",
+        line_2: "  NIGHT",
+        payload: "e",
+    },
+    ButtonAction {
+        id: "light",
+        name: "LIGHT",
+        line_1: "TURN ON/OFF LIGHT",
+        line_2: "",
+        payload: "f",
+    },
+    ButtonAction {
+        id: "function_g",
+        name: "FUNCTION G",
+        line_1: "EMPTY FUNCTION",
+        line_2: "",
+        payload: "g",
+    },
+    ButtonAction {
+        id: "function_h",
+        name: "FUNCTION H",
+        line_1: "EMPTY FUNCTION",
+        line_2: "",
+        payload: "h",
+    },
+];
+
+/// Labels of the buttons that can be reassigned, A is kept for the display and menu
+pub const BUTTON_NAMES: [&str; 7] = ["B", "C", "D", "E", "F", "G", "H"];
+
+/// Index into [`ACTIONS`] for each of the buttons B..H
+pub type Assignments = [usize; 7];
+
+pub const DEFAULT_ASSIGNMENTS: Assignments = [0, 1, 2, 3, 4, 5, 6];
+
+/// The action with this id or legacy payload, e.g. "light_day" or "d"
+pub fn find(name: &str) -> Option<&'static ButtonAction> {
+    let name = name.trim();
+    ACTIONS
+        .iter()
+        .find(|action| action.id == name || action.payload == name)
+}
+
+/// The action a button runs, falling back to its default if the stored index is stale
+pub fn assigned(assignments: &Assignments, button: usize) -> &'static ButtonAction {
+    ACTIONS
+        .get(assignments[button])
+        .unwrap_or(&ACTIONS[DEFAULT_ASSIGNMENTS[button]])
+}
@@ -0,0 +1,479 @@
+use crate::timezone::Tz;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const SNOOZE_MINUTES: i64 = 9;
+/// Stop ringing by itself if nobody is around to press a button
+pub const RING_TIMEOUT_MINUTES: i64 = 10;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const FULL_DAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Set of weekdays, bit 0 is Monday. Written as a list of day names, where
+/// "daily", "weekdays" and "weekends" are accepted as shorthands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    pub const DAILY: Weekdays = Weekdays(0b111_1111);
+    pub const WEEKDAYS: Weekdays = Weekdays(0b001_1111);
+    pub const WEEKENDS: Weekdays = Weekdays(0b110_0000);
+
+    pub fn contains(&self, day: Weekday) -> bool {
+        self.0 & (1 << day.num_days_from_monday()) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// One letter per day for the LCD, e.g. "MTWTF--"
+    pub fn letters(&self) -> String {
+        "MTWTFSS"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| if self.0 & (1 << i) != 0 { c } else { '-' })
+            .collect()
+    }
+
+    /// Parses a name, a shorthand or a comma separated list of them, e.g.
+    /// "mon", "weekends" or "mon,wed,fri". Days may be shortened as long as
+    /// only one day starts that way, so "tu" is Tuesday but "t" is an error.
+    pub fn parse(names: &str) -> Result<Weekdays> {
+        if names.contains(',') {
+            return names
+                .split(',')
+                .map(Weekdays::parse)
+                .try_fold(Weekdays::default(), |acc, days| {
+                    Ok(Weekdays(acc.0 | days?.0))
+                });
+        }
+
+        let name = names.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "daily" | "everyday" => Ok(Weekdays::DAILY),
+            "weekdays" => Ok(Weekdays::WEEKDAYS),
+            "weekends" => Ok(Weekdays::WEEKENDS),
+            _ => {
+                let mut days = FULL_DAY_NAMES
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, day)| !name.is_empty() && day.starts_with(&name));
+                match (days.next(), days.next()) {
+                    (Some((i, _)), None) => Ok(Weekdays(1 << i)),
+                    _ => bail!("Unknown day \"{}\"", name),
+                }
+            }
+        }
+    }
+}
+
+/// Written the way [`Weekdays::parse`] reads it back, e.g. "weekdays" or "mon,wed"
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Weekdays::DAILY => write!(f, "daily"),
+            Weekdays::WEEKDAYS => write!(f, "weekdays"),
+            Weekdays::WEEKENDS => write!(f, "weekends"),
+            days => write!(f, "{}", Vec::<String>::from(days).join(",")),
+        }
+    }
+}
+
+impl TryFrom<Vec<String>> for Weekdays {
+    type Error = anyhow::Error;
+
+    fn try_from(names: Vec<String>) -> Result<Self> {
+        let mut days = Weekdays::default();
+        for name in names {
+            days.0 |= Weekdays::parse(&name)?.0;
+        }
+        Ok(days)
+    }
+}
+
+impl From<Weekdays> for Vec<String> {
+    fn from(days: Weekdays) -> Self {
+        DAY_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| days.0 & (1 << i) != 0)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub hour: u32,
+    pub minute: u32,
+    /// Days the alarm repeats on, empty for a one-off alarm
+    #[serde(default)]
+    pub days: Weekdays,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Alarm {
+    pub fn validate(&self) -> Result<()> {
+        if self.hour > 23 || self.minute > 59 {
+            bail!("Invalid alarm time {}:{}", self.hour, self.minute)
+        }
+        Ok(())
+    }
+
+    /// First time strictly after `after` at which the alarm goes off
+    pub fn next_fire(&self, after: &DateTime<Utc>, tz: &Tz) -> Option<DateTime<Utc>> {
+        if !self.enabled {
+            return None;
+        }
+        let time = NaiveTime::from_hms_opt(self.hour, self.minute, 0)?;
+        let today = tz.to_local(after).date_naive();
+
+        // A week and a day covers every weekday plus a DST shift around midnight
+        (0..=8)
+            .map(|offset| today + Duration::days(offset))
+            .filter(|date| self.days.is_empty() || self.days.contains(date.weekday()))
+            .map(|date| tz.from_local(&date.and_time(time)))
+            .find(|fire| fire > after)
+    }
+}
+
+/// What Alarm1 on the DS3231 is currently set for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pending {
+    Alarm(usize),
+    Snooze(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ringing {
+    pub index: usize,
+    pub since: DateTime<Utc>,
+}
+
+/// User alarms and the ringing/snooze state around them. Only decides what
+/// should happen when; programming the RTC and persisting is up to the caller.
+#[derive(Default)]
+pub struct AlarmClock {
+    alarms: Vec<Alarm>,
+    pending: Option<(Pending, DateTime<Utc>)>,
+    snoozed: Option<(usize, DateTime<Utc>)>,
+    ringing: Option<Ringing>,
+}
+
+impl AlarmClock {
+    pub fn new(alarms: Vec<Alarm>) -> Self {
+        AlarmClock {
+            alarms,
+            ..Default::default()
+        }
+    }
+
+    pub fn alarms(&self) -> &[Alarm] {
+        &self.alarms
+    }
+
+    pub fn add(&mut self, alarm: Alarm) -> Result<()> {
+        alarm.validate()?;
+        self.alarms.push(alarm);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Alarm> {
+        if index >= self.alarms.len() {
+            bail!("No alarm at index {}", index)
+        }
+        // Indexes shift, so anything referring to them is stale now
+        self.snoozed = None;
+        self.ringing = None;
+        Ok(self.alarms.remove(index))
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> Result<()> {
+        match self.alarms.get_mut(index) {
+            Some(alarm) => {
+                alarm.enabled = enabled;
+                Ok(())
+            }
+            None => bail!("No alarm at index {}", index),
+        }
+    }
+
+    pub fn ringing(&self) -> Option<(Ringing, &Alarm)> {
+        let ringing = self.ringing?;
+        Some((ringing, self.alarms.get(ringing.index)?))
+    }
+
+    /// Work out the next time the RTC alarm has to go off, if any
+    pub fn schedule(&mut self, now: &DateTime<Utc>, tz: &Tz) -> Option<DateTime<Utc>> {
+        let next_alarm = self
+            .alarms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, alarm)| Some((Pending::Alarm(i), alarm.next_fire(now, tz)?)))
+            .min_by_key(|(_, at)| *at);
+        let snooze = self
+            .snoozed
+            .map(|(i, at)| (Pending::Snooze(i), at.max(*now)));
+
+        self.pending = match (next_alarm, snooze) {
+            (Some(a), Some(s)) => Some(if s.1 <= a.1 { s } else { a }),
+            (a, s) => a.or(s),
+        };
+        self.pending.map(|(_, at)| at)
+    }
+
+    /// The RTC alarm went off. Returns true if an alarm is now ringing.
+    pub fn on_fire(&mut self, now: &DateTime<Utc>) -> bool {
+        let index = match self.pending.take() {
+            Some((Pending::Alarm(i), _)) => i,
+            Some((Pending::Snooze(i), _)) => {
+                self.snoozed = None;
+                i
+            }
+            None => return false,
+        };
+        if index >= self.alarms.len() {
+            return false;
+        }
+        self.ringing = Some(Ringing { index, since: *now });
+        true
+    }
+
+    pub fn snooze(&mut self, now: &DateTime<Utc>) {
+        if let Some(ringing) = self.ringing.take() {
+            self.snoozed = Some((ringing.index, *now + Duration::minutes(SNOOZE_MINUTES)));
+        }
+    }
+
+    /// Stop ringing. One-off alarms are switched off, so returns true when the
+    /// alarm list changed and needs saving.
+    pub fn dismiss(&mut self) -> bool {
+        let Some(ringing) = self.ringing.take() else {
+            return false;
+        };
+        match self.alarms.get_mut(ringing.index) {
+            Some(alarm) if alarm.days.is_empty() => {
+                alarm.enabled = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the current alarm has been ringing for too long
+    pub fn timed_out(&self, now: &DateTime<Utc>) -> bool {
+        self.ringing
+            .map(|r| *now - r.since >= Duration::minutes(RING_TIMEOUT_MINUTES))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn utc(raw: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(raw, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_utc()
+    }
+
+    fn alarm(hour: u32, minute: u32, days: Weekdays) -> Alarm {
+        Alarm {
+            hour,
+            minute,
+            days,
+            label: String::new(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn weekdays_parse() {
+        assert_eq!(Weekdays::parse("mon").unwrap(), Weekdays(1));
+        assert_eq!(Weekdays::parse("Sunday").unwrap(), Weekdays(1 << 6));
+        assert_eq!(Weekdays::parse("tu").unwrap(), Weekdays(1 << 1));
+        assert_eq!(Weekdays::parse("th").unwrap(), Weekdays(1 << 3));
+        assert_eq!(
+            Weekdays::parse("mon, wed,fri").unwrap(),
+            Weekdays(0b001_0101)
+        );
+        assert_eq!(Weekdays::parse("weekends").unwrap(), Weekdays::WEEKENDS);
+        for bad in ["monkey", "t", "s", "", "mon,", "fridays"] {
+            assert!(Weekdays::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn weekdays_round_trip() {
+        for days in [Weekdays(0b000_0101), Weekdays::DAILY, Weekdays::WEEKDAYS] {
+            assert_eq!(Weekdays::parse(&days.to_string()).unwrap(), days);
+            let json = serde_json::to_string(&days).unwrap();
+            assert_eq!(serde_json::from_str::<Weekdays>(&json).unwrap(), days);
+        }
+        assert_eq!(Weekdays::WEEKDAYS.letters(), "MTWTF--");
+    }
+
+    #[test]
+    fn next_fire_on_weekdays() {
+        let tz = Tz::utc();
+        let alarm = alarm(7, 0, Weekdays::WEEKDAYS);
+        // Friday after the alarm, so Monday is next
+        let next = alarm.next_fire(&utc("2024-07-05 08:00"), &tz);
+        assert_eq!(next, Some(utc("2024-07-08 07:00")));
+        // Strictly after: firing time itself moves on to the next day
+        let next = alarm.next_fire(&utc("2024-07-08 07:00"), &tz);
+        assert_eq!(next, Some(utc("2024-07-09 07:00")));
+    }
+
+    #[test]
+    fn next_fire_one_off_and_disabled() {
+        let tz = Tz::parse("Asia/Ho_Chi_Minh").unwrap();
+        let mut alarm = alarm(6, 30, Weekdays::default());
+        let next = alarm.next_fire(&utc("2024-07-05 22:00"), &tz);
+        assert_eq!(next, Some(utc("2024-07-05 23:30")));
+        alarm.enabled = false;
+        assert_eq!(alarm.next_fire(&utc("2024-07-05 22:00"), &tz), None);
+    }
+
+    #[test]
+    fn next_fire_across_dst() {
+        let tz = Tz::parse("Europe/Berlin").unwrap();
+        let alarm = alarm(2, 30, Weekdays::DAILY);
+        // 02:30 does not exist on the spring day, it rings at 03:30 CEST
+        let next = alarm.next_fire(&utc("2024-03-30 23:00"), &tz);
+        assert_eq!(next, Some(utc("2024-03-31 01:30")));
+        // 02:30 happens twice in autumn, it rings only the first time
+        let next = alarm.next_fire(&utc("2024-10-26 23:00"), &tz);
+        assert_eq!(next, Some(utc("2024-10-27 00:30")));
+        let next = alarm.next_fire(&utc("2024-10-27 00:30"), &tz);
+        assert_eq!(next, Some(utc("2024-10-28 01:30")));
+        // A wall clock time keeps its local time over the change
+        let alarm = self::alarm(7, 0, Weekdays::DAILY);
+        let next = alarm.next_fire(&utc("2024-03-30 07:00"), &tz);
+        assert_eq!(next, Some(utc("2024-03-31 05:00")));
+    }
+
+    #[test]
+    fn schedule_picks_the_earliest() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![
+            alarm(9, 0, Weekdays::DAILY),
+            alarm(8, 0, Weekdays::DAILY),
+        ]);
+        let now = utc("2024-07-05 07:00");
+        assert_eq!(clock.schedule(&now, &tz), Some(utc("2024-07-05 08:00")));
+        assert!(clock.on_fire(&utc("2024-07-05 08:00")));
+        assert_eq!(clock.ringing().unwrap().0.index, 1);
+        // Nothing pending any more
+        assert!(!clock.on_fire(&utc("2024-07-05 08:00")));
+    }
+
+    #[test]
+    fn snooze_rings_again() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![alarm(8, 0, Weekdays::DAILY)]);
+        clock.schedule(&utc("2024-07-05 07:00"), &tz);
+        assert!(clock.on_fire(&utc("2024-07-05 08:00")));
+        clock.snooze(&utc("2024-07-05 08:01"));
+        assert!(clock.ringing().is_none());
+        assert_eq!(
+            clock.schedule(&utc("2024-07-05 08:01"), &tz),
+            Some(utc("2024-07-05 08:10"))
+        );
+        assert!(clock.on_fire(&utc("2024-07-05 08:10")));
+        assert_eq!(clock.ringing().unwrap().0.since, utc("2024-07-05 08:10"));
+        // The snooze is used up, tomorrow's alarm is next
+        clock.dismiss();
+        assert_eq!(
+            clock.schedule(&utc("2024-07-05 08:11"), &tz),
+            Some(utc("2024-07-06 08:00"))
+        );
+    }
+
+    #[test]
+    fn snooze_before_next_alarm_wins() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![
+            alarm(8, 0, Weekdays::DAILY),
+            alarm(8, 5, Weekdays::DAILY),
+        ]);
+        clock.schedule(&utc("2024-07-05 07:00"), &tz);
+        clock.on_fire(&utc("2024-07-05 08:00"));
+        clock.snooze(&utc("2024-07-05 08:00"));
+        assert_eq!(
+            clock.schedule(&utc("2024-07-05 08:00"), &tz),
+            Some(utc("2024-07-05 08:05"))
+        );
+        assert!(clock.on_fire(&utc("2024-07-05 08:05")));
+        assert_eq!(clock.ringing().unwrap().0.index, 1);
+        // The snooze of the first alarm is still there
+        clock.dismiss();
+        assert_eq!(
+            clock.schedule(&utc("2024-07-05 08:06"), &tz),
+            Some(utc("2024-07-05 08:09"))
+        );
+    }
+
+    #[test]
+    fn dismiss_switches_off_one_off_alarms() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![
+            alarm(8, 0, Weekdays::default()),
+            alarm(9, 0, Weekdays::DAILY),
+        ]);
+        clock.schedule(&utc("2024-07-05 07:00"), &tz);
+        clock.on_fire(&utc("2024-07-05 08:00"));
+        assert!(clock.dismiss());
+        assert!(!clock.alarms()[0].enabled);
+        clock.schedule(&utc("2024-07-05 08:00"), &tz);
+        clock.on_fire(&utc("2024-07-05 09:00"));
+        assert!(!clock.dismiss());
+        assert!(clock.alarms()[1].enabled);
+        assert!(!clock.dismiss());
+    }
+
+    #[test]
+    fn ringing_times_out() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![alarm(8, 0, Weekdays::DAILY)]);
+        assert!(!clock.timed_out(&utc("2024-07-05 08:00")));
+        clock.schedule(&utc("2024-07-05 07:00"), &tz);
+        clock.on_fire(&utc("2024-07-05 08:00"));
+        assert!(!clock.timed_out(&utc("2024-07-05 08:09")));
+        assert!(clock.timed_out(&utc("2024-07-05 08:10")));
+    }
+
+    #[test]
+    fn remove_drops_ringing_and_snooze() {
+        let tz = Tz::utc();
+        let mut clock = AlarmClock::new(vec![alarm(8, 0, Weekdays::DAILY)]);
+        clock.schedule(&utc("2024-07-05 07:00"), &tz);
+        clock.on_fire(&utc("2024-07-05 08:00"));
+        clock.snooze(&utc("2024-07-05 08:00"));
+        clock.remove(0).unwrap();
+        assert_eq!(clock.schedule(&utc("2024-07-05 08:00"), &tz), None);
+        assert!(clock.remove(0).is_err());
+        assert!(clock.add(alarm(24, 0, Weekdays::DAILY)).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, bail, Result};
+use std::str::FromStr;
+
+// The ESP32-C6 has GPIO0 to GPIO30
+const GPIO_COUNT: u8 = 31;
+// USB serial/JTAG and the SPI flash
+const RESERVED: [u8; 9] = [12, 13, 24, 25, 26, 27, 28, 29, 30];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pull {
+    Floating,
+    Up,
+    Down,
+}
+
+impl FromStr for Pull {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "none" | "floating" => Ok(Pull::Floating),
+            "up" => Ok(Pull::Up),
+            "down" => Ok(Pull::Down),
+            _ => bail!("Unknown pull \"{}\"", name),
+        }
+    }
+}
+
+/// Level a button pin is at while the button is held
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActiveLevel {
+    High,
+    Low,
+}
+
+impl FromStr for ActiveLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "high" => Ok(ActiveLevel::High),
+            "low" => Ok(ActiveLevel::Low),
+            _ => bail!("Unknown active level \"{}\"", name),
+        }
+    }
+}
+
+/// When a button is handled. Buttons B to H act on the edge, A acts on the
+/// press only, the main loop then times the hold to tell a long press from a
+/// short one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Press,
+    Release,
+}
+
+impl FromStr for Edge {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "press" => Ok(Edge::Press),
+            "release" => Ok(Edge::Release),
+            _ => bail!("Unknown edge \"{}\"", name),
+        }
+    }
+}
+
+/// How a button is wired. Common ground wiring is active low with a pull-up,
+/// common 3.3V wiring active high with a pull-down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ButtonPin {
+    pub gpio: u8,
+    pub pull: Pull,
+    pub active: ActiveLevel,
+    pub edge: Edge,
+}
+
+impl ButtonPin {
+    const fn rev1(gpio: u8) -> Self {
+        ButtonPin {
+            gpio,
+            pull: Pull::Down,
+            active: ActiveLevel::High,
+            edge: Edge::Release,
+        }
+    }
+}
+
+/// Which GPIOs a PCB revision wires to what
+#[derive(Clone, Debug, PartialEq)]
+pub struct Board {
+    pub name: String,
+    /// Buttons A to H
+    pub buttons: [ButtonPin; 8],
+    pub sda: u8,
+    pub scl: u8,
+    /// INT/SQW output of the DS3231
+    pub sqw: u8,
+}
+
+impl Board {
+    /// The first PCB, where buttons connect their pin to 3.3V
+    pub fn rev1() -> Self {
+        let mut buttons = [18, 19, 20, 21, 22, 23, 2, 3].map(ButtonPin::rev1);
+        buttons[0].edge = Edge::Press;
+        Board {
+            name: "rev1".to_string(),
+            buttons,
+            sda: 6,
+            scl: 7,
+            sqw: 10,
+        }
+    }
+
+    /// Every pin has to exist, be free for general use and be used only once.
+    /// A pull towards the active level would hold a button pressed. A has to
+    /// interrupt on the press only: a release edge would reach the menu as an
+    /// extra Back.
+    pub fn validate(&self) -> Result<()> {
+        for (i, button) in self.buttons.iter().enumerate() {
+            let name = (b'A' + i as u8) as char;
+            match (button.pull, button.active) {
+                (Pull::Up, ActiveLevel::High) | (Pull::Down, ActiveLevel::Low) => {
+                    bail!("Button {} is pulled to its active level", name)
+                }
+                _ => {}
+            }
+            if i == 0 && button.edge != Edge::Press {
+                bail!("Button A has to act on the press")
+            }
+        }
+        let pins: Vec<u8> = self
+            .buttons
+            .iter()
+            .map(|button| button.gpio)
+            .chain([self.sda, self.scl, self.sqw])
+            .collect();
+        for (i, pin) in pins.iter().enumerate() {
+            if *pin >= GPIO_COUNT {
+                bail!("GPIO{} does not exist", pin)
+            }
+            if RESERVED.contains(pin) {
+                bail!("GPIO{} is reserved for USB or flash", pin)
+            }
+            if pins[..i].contains(pin) {
+                bail!("GPIO{} is used twice", pin)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One value for every button, or eight comma separated ones for A to H
+pub fn parse_each<T: FromStr<Err = anyhow::Error> + Copy>(raw: &str) -> Result<[T; 8]> {
+    let values = raw.split(',').map(str::parse).collect::<Result<Vec<T>>>()?;
+    match values[..] {
+        [value] => Ok([value; 8]),
+        _ => values
+            .try_into()
+            .map_err(|_| anyhow!("Expected 1 or 8 values in \"{}\"", raw)),
+    }
+}
+
+/// A comma separated list of exactly N GPIO numbers
+pub fn parse_pins<const N: usize>(raw: &str) -> Result<[u8; N]> {
+    let pins: Vec<u8> = raw
+        .split(',')
+        .map(|pin| {
+            let pin = pin.trim();
+            pin.trim_start_matches("gpio")
+                .parse()
+                .map_err(|_| anyhow!("Invalid GPIO \"{}\"", pin))
+        })
+        .collect::<Result<_>>()?;
+    pins.try_into()
+        .map_err(|_| anyhow!("Expected {} GPIOs in \"{}\"", N, raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(board: &Board) -> String {
+        board.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn rev1_is_valid() {
+        Board::rev1().validate().unwrap();
+    }
+
+    #[test]
+    fn pins_have_to_be_usable_once() {
+        let mut board = Board::rev1();
+        board.sqw = 12;
+        assert_eq!(error(&board), "GPIO12 is reserved for USB or flash");
+        board.sqw = 31;
+        assert_eq!(error(&board), "GPIO31 does not exist");
+        board.sqw = 18;
+        assert_eq!(error(&board), "GPIO18 is used twice");
+        board.sqw = 10;
+        board.buttons[7].gpio = 6;
+        assert_eq!(error(&board), "GPIO6 is used twice");
+    }
+
+    #[test]
+    fn pull_towards_the_active_level_is_refused() {
+        let mut board = Board::rev1();
+        board.buttons[2].pull = Pull::Up;
+        assert_eq!(error(&board), "Button C is pulled to its active level");
+        board.buttons[2].active = ActiveLevel::Low;
+        board.validate().unwrap();
+        board.buttons[2].pull = Pull::Floating;
+        board.validate().unwrap();
+    }
+
+    #[test]
+    fn a_acts_on_the_press() {
+        let mut board = Board::rev1();
+        board.buttons[0].edge = Edge::Release;
+        assert_eq!(error(&board), "Button A has to act on the press");
+        board.buttons[0].edge = Edge::Press;
+        board.buttons[1].edge = Edge::Press;
+        board.validate().unwrap();
+    }
+
+    #[test]
+    fn each_takes_one_or_eight_values() {
+        assert_eq!(parse_each::<Pull>(" up ").unwrap(), [Pull::Up; 8]);
+        let levels = parse_each::<ActiveLevel>("high,low,high,low,high,low,high,low").unwrap();
+        assert_eq!(levels[1], ActiveLevel::Low);
+        assert_eq!(
+            parse_each::<Edge>("press,release").unwrap_err().to_string(),
+            "Expected 1 or 8 values in \"press,release\""
+        );
+        assert!(parse_each::<Pull>("sideways").is_err());
+        assert!(parse_each::<Edge>("both").is_err());
+        assert!(parse_each::<Pull>("").is_err());
+    }
+
+    #[test]
+    fn pins_parse() {
+        assert_eq!(parse_pins::<2>("6, gpio7").unwrap(), [6, 7]);
+        assert_eq!(
+            parse_pins::<2>("6").unwrap_err().to_string(),
+            "Expected 2 GPIOs in \"6\""
+        );
+        assert_eq!(
+            parse_pins::<1>("d4").unwrap_err().to_string(),
+            "Invalid GPIO \"d4\""
+        );
+    }
+}
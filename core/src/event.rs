@@ -0,0 +1,380 @@
+use crate::actions::{self, ButtonAction, BUTTON_NAMES};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Bumped whenever a field changes meaning or goes away, new fields keep it
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// What `button` holds in events sent for a schedule entry
+pub const SCHEDULE_NAME: &str = "schedule";
+
+static SEQ: AtomicU32 = AtomicU32::new(0);
+static PRESSES: [AtomicU32; 7] = [const { AtomicU32::new(0) }; 7];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    Press,
+    /// Sent by a schedule entry instead of a person
+    Scheduled,
+}
+
+impl Gesture {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gesture::Press => "press",
+            Gesture::Scheduled => "scheduled",
+        }
+    }
+}
+
+/// A button press as published on the command topic. Consumers go by the
+/// action, so rewiring or reassigning buttons does not break them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ButtonEvent {
+    pub v: u32,
+    pub device_id: String,
+    pub button: String,
+    pub gesture: Gesture,
+    pub action: String,
+    /// Unix time in seconds, from the RTC
+    pub ts: i64,
+    /// Counts up from boot, so consumers can spot lost or repeated events
+    pub seq: u32,
+}
+
+/// What goes on the command topic: the single letter older consumers expect,
+/// or a [`ButtonEvent`] as JSON
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PayloadFormat {
+    #[default]
+    Legacy,
+    Json,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "legacy" => Ok(PayloadFormat::Legacy),
+            "json" => Ok(PayloadFormat::Json),
+            _ => bail!("Unknown payload format \"{}\"", name),
+        }
+    }
+}
+
+/// Where and what to publish for a button
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+}
+
+pub struct Encoder {
+    format: PayloadFormat,
+    device_id: String,
+    command_topic: String,
+    topic_template: String,
+    prefix: String,
+    /// Topics by action id, taking the place of the template for that action
+    action_topics: Vec<(String, String)>,
+}
+
+impl Encoder {
+    /// Without a topic template every button publishes on `command_topic`
+    pub fn new(
+        format: PayloadFormat,
+        device_id: &str,
+        command_topic: &str,
+        topic_template: &str,
+        prefix: &str,
+    ) -> Self {
+        Encoder {
+            format,
+            device_id: device_id.to_string(),
+            command_topic: command_topic.to_string(),
+            topic_template: topic_template.to_string(),
+            prefix: prefix.to_string(),
+            action_topics: Vec::new(),
+        }
+    }
+
+    /// Give actions their own topic, from comma separated `action=topic`
+    /// pairs such as `light_day=home/light/set`. The topics take the same
+    /// placeholders as the template. Nothing changes when one is invalid.
+    pub fn set_action_topics(&mut self, raw: &str) -> Result<()> {
+        let mut action_topics = Vec::new();
+        for pair in raw.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (id, topic) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected action=topic in \"{}\"", pair))?;
+            let (id, topic) = (id.trim(), topic.trim());
+            let Some(action) = actions::ACTIONS.iter().find(|action| action.id == id) else {
+                bail!("Unknown action \"{}\"", id)
+            };
+            if topic.is_empty() {
+                bail!("No topic for action \"{}\"", id)
+            }
+            action_topics.push((action.id.to_string(), topic.to_string()));
+        }
+        self.action_topics = action_topics;
+        Ok(())
+    }
+
+    /// Message for a press of one of the buttons B..H
+    pub fn encode(
+        &self,
+        button: usize,
+        gesture: Gesture,
+        action: &ButtonAction,
+        ts: i64,
+    ) -> Result<Message> {
+        let message = self.message(BUTTON_NAMES[button], gesture, action, ts)?;
+        // Only presses that make it into a message count
+        PRESSES[button].fetch_add(1, Ordering::SeqCst);
+        Ok(message)
+    }
+
+    /// Message for a schedule entry, sent like a press of a button named
+    /// [`SCHEDULE_NAME`]. Payloads that are no action go out as they are on
+    /// the command topic, which only legacy consumers understand.
+    pub fn encode_schedule(&self, payload: &str, ts: i64) -> Result<Message> {
+        match (actions::find(payload), self.format) {
+            (Some(action), _) => self.message(SCHEDULE_NAME, Gesture::Scheduled, action, ts),
+            (None, PayloadFormat::Legacy) => Ok(Message {
+                topic: self.command_topic.clone(),
+                payload: payload.to_string(),
+            }),
+            (None, PayloadFormat::Json) => bail!("No action \"{}\" for a JSON event", payload),
+        }
+    }
+
+    fn message(
+        &self,
+        name: &str,
+        gesture: Gesture,
+        action: &ButtonAction,
+        ts: i64,
+    ) -> Result<Message> {
+        Ok(Message {
+            topic: self.topic(name, gesture, action),
+            payload: self.payload(name, gesture, action, ts)?,
+        })
+    }
+
+    /// The action's own topic, else the template, else the command topic.
+    /// Fills in `{prefix}`, `{device_id}`, `{name}` (the button), `{action}`
+    /// and `{gesture}`, e.g. `{prefix}/{device_id}/button/{name}/{gesture}`
+    fn topic(&self, name: &str, gesture: Gesture, action: &ButtonAction) -> String {
+        let template = self
+            .action_topics
+            .iter()
+            .find(|(id, _)| id == action.id)
+            .map_or(self.topic_template.as_str(), |(_, topic)| topic.as_str());
+        if template.is_empty() {
+            return self.command_topic.clone();
+        }
+        template
+            .replace("{prefix}", &self.prefix)
+            .replace("{device_id}", &self.device_id)
+            .replace("{name}", &name.to_ascii_lowercase())
+            .replace("{action}", action.id)
+            .replace("{gesture}", gesture.name())
+    }
+
+    fn payload(
+        &self,
+        name: &str,
+        gesture: Gesture,
+        action: &ButtonAction,
+        ts: i64,
+    ) -> Result<String> {
+        match self.format {
+            PayloadFormat::Legacy => Ok(action.payload.to_string()),
+            PayloadFormat::Json => {
+                let event = ButtonEvent {
+                    v: SCHEMA_VERSION,
+                    device_id: self.device_id.clone(),
+                    button: name.to_string(),
+                    gesture,
+                    action: action.id.to_string(),
+                    ts,
+                    seq: SEQ.fetch_add(1, Ordering::SeqCst),
+                };
+                Ok(serde_json::to_string(&event)?)
+            }
+        }
+    }
+}
+
+/// Presses of the buttons B..H since boot
+pub fn presses() -> [u32; 7] {
+    std::array::from_fn(|i| PRESSES[i].load(Ordering::SeqCst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::ACTIONS;
+    use serde_json::{json, Value};
+
+    fn encoder(format: PayloadFormat, template: &str) -> Encoder {
+        Encoder::new(format, "a1b2c3", "home/command", template, "home")
+    }
+
+    #[test]
+    fn json_event_has_the_v1_fields() {
+        let message = encoder(PayloadFormat::Json, "")
+            .encode(1, Gesture::Press, &ACTIONS[1], 1_700_000_000)
+            .unwrap();
+        let mut event: Value = serde_json::from_str(&message.payload).unwrap();
+        assert!(event["seq"].is_u64());
+        event["seq"] = json!(0);
+        assert_eq!(
+            event,
+            json!({
+                "v": 1,
+                "device_id": "a1b2c3",
+                "button": "C",
+                "gesture": "press",
+                "action": "air_filter",
+                "ts": 1_700_000_000,
+                "seq": 0,
+            })
+        );
+        assert_eq!(message.topic, "home/command");
+    }
+
+    #[test]
+    fn json_event_round_trips() {
+        let event = ButtonEvent {
+            v: SCHEMA_VERSION,
+            device_id: "a1b2c3".to_string(),
+            button: "B".to_string(),
+            gesture: Gesture::Press,
+            action: "light_day".to_string(),
+            ts: 0,
+            seq: 7,
+        };
+        let payload = serde_json::to_string(&event).unwrap();
+        assert!(payload.contains(r#""gesture":"press""#));
+        assert_eq!(
+            serde_json::from_str::<ButtonEvent>(&payload).unwrap(),
+            event
+        );
+        assert!(serde_json::from_str::<ButtonEvent>(&payload.replace("press", "Press")).is_err());
+    }
+
+    #[test]
+    fn action_ids_are_snake_case() {
+        for action in ACTIONS {
+            assert!(action
+                .id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'));
+        }
+    }
+
+    #[test]
+    fn seq_counts_up() {
+        let encoder = encoder(PayloadFormat::Json, "");
+        let seq = |payload: &str| serde_json::from_str::<ButtonEvent>(payload).unwrap().seq;
+        let first = encoder.encode(0, Gesture::Press, &ACTIONS[0], 0).unwrap();
+        let second = encoder.encode(0, Gesture::Press, &ACTIONS[0], 0).unwrap();
+        assert!(seq(&second.payload) > seq(&first.payload));
+    }
+
+    #[test]
+    fn legacy_sends_the_letter() {
+        let message = encoder(PayloadFormat::Legacy, "")
+            .encode(0, Gesture::Press, &ACTIONS[0], 0)
+            .unwrap();
+        assert_eq!(message.payload, "b");
+        assert_eq!(message.topic, "home/command");
+    }
+
+    #[test]
+    fn topic_template_is_filled_in() {
+        let message = encoder(
+            PayloadFormat::Legacy,
+            "{prefix}/{device_id}/button/{name}/{action}/{gesture}",
+        )
+        .encode(3, Gesture::Press, &ACTIONS[4], 0)
+        .unwrap();
+        assert_eq!(message.topic, "home/a1b2c3/button/e/light/press");
+    }
+
+    #[test]
+    fn action_topic_replaces_the_template() {
+        let mut encoder = encoder(PayloadFormat::Legacy, "");
+        encoder
+            .set_action_topics("light_day = {prefix}/light/set, ac=home/ac/{gesture}")
+            .unwrap();
+        let topic = |encoder: &Encoder, i: usize| {
+            encoder
+                .encode(0, Gesture::Press, &ACTIONS[i], 0)
+                .unwrap()
+                .topic
+        };
+        assert_eq!(topic(&encoder, 2), "home/light/set");
+        assert_eq!(topic(&encoder, 0), "home/ac/press");
+        // Actions without their own topic keep the single topic
+        assert_eq!(topic(&encoder, 1), "home/command");
+
+        // An invalid list leaves the topics as they were
+        assert!(encoder
+            .set_action_topics("ac=home/ac,lamp=home/lamp")
+            .is_err());
+        assert!(encoder.set_action_topics("light_day").is_err());
+        assert!(encoder.set_action_topics("light_day=").is_err());
+        assert_eq!(topic(&encoder, 2), "home/light/set");
+        encoder.set_action_topics("").unwrap();
+        assert_eq!(topic(&encoder, 2), "home/command");
+    }
+
+    #[test]
+    fn schedule_sends_its_action() {
+        let message = encoder(PayloadFormat::Json, "{prefix}/{name}/{gesture}")
+            .encode_schedule("light_day", 1_700_000_000)
+            .unwrap();
+        assert_eq!(message.topic, "home/schedule/scheduled");
+        let event: ButtonEvent = serde_json::from_str(&message.payload).unwrap();
+        assert_eq!(event.button, SCHEDULE_NAME);
+        assert_eq!(event.gesture, Gesture::Scheduled);
+        assert_eq!(event.action, "light_day");
+        assert!(message.payload.contains(r#""gesture":"scheduled""#));
+
+        // The legacy letter finds the action too
+        let message = encoder(PayloadFormat::Legacy, "")
+            .encode_schedule("d", 0)
+            .unwrap();
+        assert_eq!(
+            (message.topic.as_str(), message.payload.as_str()),
+            ("home/command", "d")
+        );
+    }
+
+    #[test]
+    fn schedule_without_action() {
+        let message = encoder(PayloadFormat::Legacy, "{prefix}/{name}")
+            .encode_schedule("raw text", 0)
+            .unwrap();
+        assert_eq!(message.topic, "home/command");
+        assert_eq!(message.payload, "raw text");
+        assert!(encoder(PayloadFormat::Json, "")
+            .encode_schedule("raw text", 0)
+            .is_err());
+    }
+
+    #[test]
+    fn payload_format_parses() {
+        assert_eq!("".parse::<PayloadFormat>().unwrap(), PayloadFormat::Legacy);
+        assert_eq!(
+            " JSON ".parse::<PayloadFormat>().unwrap(),
+            PayloadFormat::Json
+        );
+        assert!("xml".parse::<PayloadFormat>().is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! The parts of the button board that do not touch the hardware, built and
+//! tested on the host with `cargo test` from this directory
+
+pub mod actions;
+pub mod alarm;
+pub mod board;
+pub mod event;
+pub mod menu;
+pub mod schedule;
+pub mod timezone;
@@ -0,0 +1,487 @@
+use crate::actions::{ACTIONS, BUTTON_NAMES};
+
+/// Buttons as seen by the menu. The caller maps the physical buttons onto these.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    Select,
+    Back,
+}
+
+impl Key {
+    /// A goes back, B and C move up and down, D selects
+    pub fn for_button(index: usize) -> Option<Key> {
+        match index {
+            0 => Some(Key::Back),
+            1 => Some(Key::Up),
+            2 => Some(Key::Down),
+            3 => Some(Key::Select),
+            _ => None,
+        }
+    }
+}
+
+/// Something the menu asks the main loop to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    NetworkStatus,
+    About,
+    SyncTime,
+    Timezone(&'static str),
+    Backlight(bool),
+    AssignButton { button: usize, action: usize },
+    AddAlarm { hour: u32, minute: u32 },
+    ToggleAlarm(usize),
+}
+
+pub enum Node {
+    Submenu(&'static [Entry]),
+    Action(Action),
+    /// The stored alarms, selecting one switches it on or off
+    AlarmList,
+    /// Hour and minute picker for a new alarm
+    NewAlarm,
+    /// Action picker for one of the buttons B..H
+    ButtonActions(usize),
+}
+
+pub struct Entry {
+    pub label: &'static str,
+    pub node: Node,
+}
+
+const fn entry(label: &'static str, node: Node) -> Entry {
+    Entry { label, node }
+}
+
+pub static MENU: &[Entry] = &[
+    entry("NETWORK", Node::Action(Action::NetworkStatus)),
+    entry(
+        "ALARMS",
+        Node::Submenu(&[
+            entry("NEW ALARM", Node::NewAlarm),
+            entry("ON/OFF", Node::AlarmList),
+        ]),
+    ),
+    entry(
+        "TIMEZONE",
+        Node::Submenu(&[
+            entry(
+                "HO CHI MINH",
+                Node::Action(Action::Timezone("Asia/Ho_Chi_Minh")),
+            ),
+            entry("UTC", Node::Action(Action::Timezone("UTC"))),
+            entry("TOKYO", Node::Action(Action::Timezone("Asia/Tokyo"))),
+            entry(
+                "SINGAPORE",
+                Node::Action(Action::Timezone("Asia/Singapore")),
+            ),
+            entry("SYDNEY", Node::Action(Action::Timezone("Australia/Sydney"))),
+            entry("LONDON", Node::Action(Action::Timezone("Europe/London"))),
+            entry("BERLIN", Node::Action(Action::Timezone("Europe/Berlin"))),
+            entry(
+                "NEW YORK",
+                Node::Action(Action::Timezone("America/New_York")),
+            ),
+            entry(
+                "LOS ANGELES",
+                Node::Action(Action::Timezone("America/Los_Angeles")),
+            ),
+        ]),
+    ),
+    entry(
+        "BACKLIGHT",
+        Node::Submenu(&[
+            entry("ON", Node::Action(Action::Backlight(true))),
+            entry("OFF", Node::Action(Action::Backlight(false))),
+        ]),
+    ),
+    entry(
+        "BUTTONS",
+        Node::Submenu(&[
+            entry("BUTTON B", Node::ButtonActions(0)),
+            entry("BUTTON C", Node::ButtonActions(1)),
+            entry("BUTTON D", Node::ButtonActions(2)),
+            entry("BUTTON E", Node::ButtonActions(3)),
+            entry("BUTTON F", Node::ButtonActions(4)),
+            entry("BUTTON G", Node::ButtonActions(5)),
+            entry("BUTTON H", Node::ButtonActions(6)),
+        ]),
+    ),
+    entry("SYNC TIME", Node::Action(Action::SyncTime)),
+    entry("ABOUT", Node::Action(Action::About)),
+];
+
+enum Frame {
+    List {
+        entries: &'static [Entry],
+        cursor: usize,
+    },
+    Alarms {
+        cursor: usize,
+    },
+    Buttons {
+        button: usize,
+        cursor: usize,
+    },
+    NewAlarm {
+        hour: u32,
+        minute: u32,
+        editing_minute: bool,
+    },
+    /// Status or confirmation screen, any key goes back
+    Info {
+        line_1: String,
+        line_2: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Stay,
+    Exit,
+    Run(Action),
+}
+
+/// Menu navigation state. Alarm labels are passed in on every call since the
+/// alarm list can change underneath the menu.
+pub struct Menu {
+    stack: Vec<Frame>,
+}
+
+impl Menu {
+    pub fn new() -> Self {
+        Menu {
+            stack: vec![Frame::List {
+                entries: MENU,
+                cursor: 0,
+            }],
+        }
+    }
+
+    /// Show a result screen on top of the current one
+    pub fn show_info(&mut self, line_1: &str, line_2: &str) {
+        self.stack.push(Frame::Info {
+            line_1: line_1.to_string(),
+            line_2: line_2.to_string(),
+        });
+    }
+
+    pub fn handle(&mut self, key: Key, alarms: &[String]) -> Outcome {
+        let Some(frame) = self.stack.last_mut() else {
+            return Outcome::Exit;
+        };
+
+        let next = match frame {
+            Frame::Info { .. } => None,
+            Frame::List { entries, cursor } => match key {
+                Key::Up | Key::Down => {
+                    *cursor = step(*cursor, entries.len(), key);
+                    return Outcome::Stay;
+                }
+                Key::Back => None,
+                Key::Select => match entries[*cursor].node {
+                    Node::Submenu(entries) => {
+                        self.stack.push(Frame::List { entries, cursor: 0 });
+                        return Outcome::Stay;
+                    }
+                    Node::Action(action) => return Outcome::Run(action),
+                    Node::AlarmList => {
+                        self.stack.push(Frame::Alarms { cursor: 0 });
+                        return Outcome::Stay;
+                    }
+                    Node::NewAlarm => {
+                        self.stack.push(Frame::NewAlarm {
+                            hour: 6,
+                            minute: 0,
+                            editing_minute: false,
+                        });
+                        return Outcome::Stay;
+                    }
+                    Node::ButtonActions(button) => {
+                        self.stack.push(Frame::Buttons { button, cursor: 0 });
+                        return Outcome::Stay;
+                    }
+                },
+            },
+            Frame::Alarms { cursor } => match key {
+                Key::Up | Key::Down => {
+                    *cursor = step(*cursor, alarms.len(), key);
+                    return Outcome::Stay;
+                }
+                Key::Select if *cursor < alarms.len() => {
+                    return Outcome::Run(Action::ToggleAlarm(*cursor))
+                }
+                _ => None,
+            },
+            Frame::Buttons { button, cursor } => match key {
+                Key::Up | Key::Down => {
+                    *cursor = step(*cursor, ACTIONS.len(), key);
+                    return Outcome::Stay;
+                }
+                Key::Select => Some(Action::AssignButton {
+                    button: *button,
+                    action: *cursor,
+                }),
+                Key::Back => None,
+            },
+            Frame::NewAlarm {
+                hour,
+                minute,
+                editing_minute,
+            } => match (key, *editing_minute) {
+                (Key::Up | Key::Down, false) => {
+                    *hour = adjust(*hour, 24, key);
+                    return Outcome::Stay;
+                }
+                (Key::Up | Key::Down, true) => {
+                    *minute = adjust(*minute, 60, key);
+                    return Outcome::Stay;
+                }
+                (Key::Select, false) => {
+                    *editing_minute = true;
+                    return Outcome::Stay;
+                }
+                (Key::Back, true) => {
+                    *editing_minute = false;
+                    return Outcome::Stay;
+                }
+                (Key::Select, true) => Some(Action::AddAlarm {
+                    hour: *hour,
+                    minute: *minute,
+                }),
+                (Key::Back, false) => None,
+            },
+        };
+
+        // Everything else leaves the current frame
+        self.stack.pop();
+        match next {
+            Some(action) => Outcome::Run(action),
+            None if self.stack.is_empty() => Outcome::Exit,
+            None => Outcome::Stay,
+        }
+    }
+
+    /// The two LCD lines for the current state
+    pub fn render(&self, alarms: &[String]) -> (String, String) {
+        match self.stack.last() {
+            None => (String::new(), String::new()),
+            Some(Frame::Info { line_1, line_2 }) => (line_1.clone(), line_2.clone()),
+            Some(Frame::List { entries, cursor }) => list_lines(
+                &entries.iter().map(|e| e.label).collect::<Vec<_>>(),
+                *cursor,
+            ),
+            Some(Frame::Alarms { .. }) if alarms.is_empty() => {
+                ("NO ALARMS".to_string(), String::new())
+            }
+            Some(Frame::Alarms { cursor }) => list_lines(
+                &alarms.iter().map(|a| a.as_str()).collect::<Vec<_>>(),
+                *cursor,
+            ),
+            Some(Frame::Buttons { button, cursor }) => {
+                let (line_1, _) =
+                    list_lines(&ACTIONS.iter().map(|a| a.name).collect::<Vec<_>>(), *cursor);
+                (line_1, format!("  FOR BUTTON {}", BUTTON_NAMES[*button]))
+            }
+            Some(Frame::NewAlarm {
+                hour,
+                minute,
+                editing_minute,
+            }) => {
+                let time = if *editing_minute {
+                    format!(" {:02}:[{:02}]", hour, minute)
+                } else {
+                    format!("[{:02}]:{:02}", hour, minute)
+                };
+                ("NEW ALARM".to_string(), time)
+            }
+        }
+    }
+}
+
+impl Default for Menu {
+    fn default() -> Self {
+        Menu::new()
+    }
+}
+
+/// Move a cursor up or down, wrapping around at both ends
+fn step(cursor: usize, len: usize, key: Key) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    match key {
+        Key::Up => (cursor + len - 1) % len,
+        Key::Down => (cursor + 1) % len,
+        _ => cursor,
+    }
+}
+
+/// Change a value with Up counting up, wrapping around at `len`
+fn adjust(value: u32, len: u32, key: Key) -> u32 {
+    match key {
+        Key::Up => (value + 1) % len,
+        Key::Down => (value + len - 1) % len,
+        _ => value,
+    }
+}
+
+/// Selected item on the first line and the one after it on the second
+fn list_lines(items: &[&str], cursor: usize) -> (String, String) {
+    let line_1 = items
+        .get(cursor)
+        .map(|item| format!("> {}", item))
+        .unwrap_or_default();
+    let line_2 = items
+        .get(cursor + 1)
+        .map(|item| format!("  {}", item))
+        .unwrap_or_default();
+    (line_1, line_2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(menu: &mut Menu, keys: &[Key]) -> Outcome {
+        let mut outcome = Outcome::Stay;
+        for key in keys {
+            outcome = menu.handle(*key, &[]);
+        }
+        outcome
+    }
+
+    fn line_1(menu: &Menu) -> String {
+        menu.render(&[]).0
+    }
+
+    #[test]
+    fn buttons_map_to_keys() {
+        let keys: Vec<_> = (0..8).map(Key::for_button).collect();
+        assert_eq!(
+            keys,
+            [
+                Some(Key::Back),
+                Some(Key::Up),
+                Some(Key::Down),
+                Some(Key::Select),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_and_wraps() {
+        let mut menu = Menu::new();
+        assert_eq!(menu.render(&[]), ("> NETWORK".into(), "  ALARMS".into()));
+        assert_eq!(press(&mut menu, &[Key::Down]), Outcome::Stay);
+        assert_eq!(line_1(&menu), "> ALARMS");
+        press(&mut menu, &[Key::Up, Key::Up]);
+        assert_eq!(menu.render(&[]), ("> ABOUT".into(), String::new()));
+        press(&mut menu, &[Key::Down]);
+        assert_eq!(line_1(&menu), "> NETWORK");
+    }
+
+    #[test]
+    fn actions_run_and_stay() {
+        let mut menu = Menu::new();
+        assert_eq!(
+            press(&mut menu, &[Key::Select]),
+            Outcome::Run(Action::NetworkStatus)
+        );
+        menu.show_info("CONNECTED", "10.0.0.2");
+        assert_eq!(menu.render(&[]), ("CONNECTED".into(), "10.0.0.2".into()));
+        // Any key closes the info screen
+        assert_eq!(press(&mut menu, &[Key::Down]), Outcome::Stay);
+        assert_eq!(line_1(&menu), "> NETWORK");
+    }
+
+    #[test]
+    fn back_goes_up_one_level_then_exits() {
+        let mut menu = Menu::new();
+        press(&mut menu, &[Key::Down, Key::Down, Key::Select]);
+        assert_eq!(line_1(&menu), "> HO CHI MINH");
+        assert_eq!(
+            press(&mut menu, &[Key::Down, Key::Select]),
+            Outcome::Run(Action::Timezone("UTC"))
+        );
+        // One press of A is one Back: the menu is still open after it
+        assert_eq!(press(&mut menu, &[Key::Back]), Outcome::Stay);
+        assert_eq!(line_1(&menu), "> TIMEZONE");
+        assert_eq!(press(&mut menu, &[Key::Back]), Outcome::Exit);
+        assert_eq!(press(&mut menu, &[Key::Select]), Outcome::Exit);
+    }
+
+    #[test]
+    fn new_alarm_editing() {
+        let mut menu = Menu::new();
+        press(&mut menu, &[Key::Down, Key::Select, Key::Select]);
+        assert_eq!(menu.render(&[]), ("NEW ALARM".into(), "[06]:00".into()));
+        press(&mut menu, &[Key::Up, Key::Up]);
+        assert_eq!(menu.render(&[]).1, "[08]:00");
+        press(&mut menu, &[Key::Select, Key::Down]);
+        assert_eq!(menu.render(&[]).1, " 08:[59]");
+        // Back while on the minutes returns to the hour
+        press(&mut menu, &[Key::Back]);
+        assert_eq!(menu.render(&[]).1, "[08]:59");
+        assert_eq!(
+            press(&mut menu, &[Key::Select, Key::Up, Key::Select]),
+            Outcome::Run(Action::AddAlarm { hour: 8, minute: 0 })
+        );
+        assert_eq!(line_1(&menu), "> NEW ALARM");
+    }
+
+    #[test]
+    fn new_alarm_hour_wraps_and_back_leaves() {
+        let mut menu = Menu::new();
+        press(&mut menu, &[Key::Down, Key::Select, Key::Select]);
+        press(&mut menu, &[Key::Down; 7]);
+        assert_eq!(menu.render(&[]).1, "[23]:00");
+        assert_eq!(press(&mut menu, &[Key::Back]), Outcome::Stay);
+        assert_eq!(line_1(&menu), "> NEW ALARM");
+    }
+
+    #[test]
+    fn alarm_list() {
+        let alarms = vec!["* 06:30 MTWTF--".to_string(), "  07:00 ONCE".to_string()];
+        let mut menu = Menu::new();
+        press(&mut menu, &[Key::Down, Key::Select, Key::Down, Key::Select]);
+        assert_eq!(menu.render(&[]).0, "NO ALARMS");
+        assert_eq!(
+            menu.render(&alarms),
+            ("> * 06:30 MTWTF--".into(), "    07:00 ONCE".into())
+        );
+        assert_eq!(menu.handle(Key::Down, &alarms), Outcome::Stay);
+        assert_eq!(
+            menu.handle(Key::Select, &alarms),
+            Outcome::Run(Action::ToggleAlarm(1))
+        );
+        // Without alarms Select just leaves
+        assert_eq!(press(&mut menu, &[Key::Up, Key::Select]), Outcome::Stay);
+        assert_eq!(line_1(&menu), "> ON/OFF");
+    }
+
+    #[test]
+    fn assign_button() {
+        let mut menu = Menu::new();
+        press(&mut menu, &[Key::Down; 4]);
+        press(&mut menu, &[Key::Select, Key::Down, Key::Select]);
+        assert_eq!(
+            menu.render(&[]),
+            (format!("> {}", ACTIONS[0].name), "  FOR BUTTON C".into())
+        );
+        assert_eq!(
+            press(&mut menu, &[Key::Down, Key::Select]),
+            Outcome::Run(Action::AssignButton {
+                button: 1,
+                action: 1
+            })
+        );
+        assert_eq!(line_1(&menu), "> BUTTON C");
+    }
+}
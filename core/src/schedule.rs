@@ -0,0 +1,288 @@
+use crate::alarm::Weekdays;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDateTime, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Command published at a set local time, written as
+/// `[days] HH:MM publish <payload>`, e.g. "weekdays 06:30 publish light_day".
+/// Without days the entry runs every day. The payload names an action by id
+/// or legacy letter and is sent like a button press of that action.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    pub days: Weekdays,
+    pub time: NaiveTime,
+    pub payload: String,
+}
+
+impl Schedule {
+    pub fn is_due(&self, local: &NaiveDateTime) -> bool {
+        self.days.contains(local.weekday())
+            && self.time.hour() == local.hour()
+            && self.time.minute() == local.minute()
+    }
+
+    /// First local time strictly after `after` at which the entry runs
+    pub fn next_run(&self, after: &NaiveDateTime) -> Option<NaiveDateTime> {
+        (0..=7)
+            .map(|offset| (after.date() + Duration::days(offset)).and_time(self.time))
+            .filter(|run| self.days.contains(run.weekday()))
+            .find(|run| run > after)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(entry: &str) -> Result<Self> {
+        let mut words = entry.split_whitespace().peekable();
+
+        let days = match words.peek() {
+            Some(word) if !word.contains(':') => {
+                let days = Weekdays::parse(word)?;
+                words.next();
+                days
+            }
+            _ => Weekdays::DAILY,
+        };
+        if days.is_empty() {
+            bail!("No days in schedule \"{}\"", entry)
+        }
+
+        let time = words
+            .next()
+            .and_then(|time| NaiveTime::parse_from_str(time, "%H:%M").ok())
+            .ok_or_else(|| anyhow!("Expected HH:MM in schedule \"{}\"", entry))?;
+
+        if words.next() != Some("publish") {
+            bail!("Expected \"publish\" in schedule \"{}\"", entry)
+        }
+        let payload = words.collect::<Vec<_>>().join(" ");
+        if payload.is_empty() {
+            bail!("Missing payload in schedule \"{}\"", entry)
+        }
+
+        Ok(Schedule {
+            days,
+            time,
+            payload,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} publish {}",
+            self.days,
+            self.time.format("%H:%M"),
+            self.payload
+        )
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: String) -> Result<Self> {
+        entry.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+/// Schedule entries evaluated on the RTC minute tick
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    /// Latest minute evaluated, so the hour repeated when DST ends does not
+    /// run entries twice
+    last_tick: Option<DateTime<FixedOffset>>,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<Schedule>) -> Self {
+        Scheduler {
+            schedules,
+            last_tick: None,
+        }
+    }
+
+    pub fn schedules(&self) -> &[Schedule] {
+        &self.schedules
+    }
+
+    pub fn add(&mut self, schedule: Schedule) {
+        self.schedules.push(schedule);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Schedule> {
+        if index >= self.schedules.len() {
+            bail!("No schedule at index {}", index)
+        }
+        Ok(self.schedules.remove(index))
+    }
+
+    /// Entries due at this minute. Local time going back while UTC moves on
+    /// is DST ending and is skipped until local time catches up. UTC going
+    /// back means the clock was set back, after NTP or by hand, and entries
+    /// run again from there.
+    pub fn tick(&mut self, now: &DateTime<FixedOffset>) -> Vec<&Schedule> {
+        let minute = now
+            .with_second(0)
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap_or(*now);
+        if self
+            .last_tick
+            .is_some_and(|last| minute.naive_local() <= last.naive_local() && minute >= last)
+        {
+            return Vec::new();
+        }
+        self.last_tick = Some(minute);
+
+        self.schedules
+            .iter()
+            .filter(|s| s.is_due(&minute.naive_local()))
+            .collect()
+    }
+
+    /// The entry running soonest after `after`, with its local run time
+    pub fn next(&self, after: &NaiveDateTime) -> Option<(&Schedule, NaiveDateTime)> {
+        self.schedules
+            .iter()
+            .filter_map(|s| Some((s, s.next_run(after)?)))
+            .min_by_key(|(_, run)| *run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timezone::Tz;
+
+    fn local(raw: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(raw, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// The local time at this UTC minute
+    fn at(tz: &Tz, utc: &str) -> DateTime<FixedOffset> {
+        tz.to_local(&local(utc).and_utc())
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entry: Schedule = "weekdays 06:30 publish light_day".parse().unwrap();
+        assert_eq!(entry.days, Weekdays::WEEKDAYS);
+        assert_eq!(entry.time, NaiveTime::from_hms_opt(6, 30, 0).unwrap());
+        assert_eq!(entry.payload, "light_day");
+
+        let entry: Schedule = "  21:00   publish  good  night ".parse().unwrap();
+        assert_eq!(entry.days, Weekdays::DAILY);
+        assert_eq!(entry.payload, "good night");
+
+        let entry: Schedule = "sat,sun 09:15 publish d".parse().unwrap();
+        assert_eq!(entry.days, Weekdays::WEEKENDS);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        for entry in [
+            "",
+            "06:30",
+            "06:30 publish",
+            "06:30 send d",
+            "25:00 publish d",
+            "6h30 publish d",
+            "someday 06:30 publish d",
+            "weekdays publish d",
+        ] {
+            assert!(entry.parse::<Schedule>().is_err(), "{:?} parsed", entry);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for raw in [
+            "weekdays 06:30 publish d",
+            "mon,fri 22:05 publish light off",
+        ] {
+            let entry: Schedule = raw.parse().unwrap();
+            assert_eq!(entry.to_string(), raw);
+            let json = serde_json::to_string(&entry).unwrap();
+            assert_eq!(json, format!("\"{}\"", raw));
+            assert_eq!(serde_json::from_str::<Schedule>(&json).unwrap(), entry);
+        }
+        assert!(serde_json::from_str::<Schedule>("\"06:30\"").is_err());
+    }
+
+    #[test]
+    fn next_run() {
+        let entry: Schedule = "weekdays 06:30 publish d".parse().unwrap();
+        // Friday evening, so Monday morning is next
+        let next = entry.next_run(&local("2024-07-05 20:00"));
+        assert_eq!(next, Some(local("2024-07-08 06:30")));
+        let next = entry.next_run(&local("2024-07-08 06:30"));
+        assert_eq!(next, Some(local("2024-07-09 06:30")));
+    }
+
+    #[test]
+    fn tick_runs_each_minute_once() {
+        let tz = Tz::utc();
+        let mut scheduler = Scheduler::new(vec![
+            "06:30 publish b".parse().unwrap(),
+            "sun 06:30 publish c".parse().unwrap(),
+            "06:31 publish d".parse().unwrap(),
+        ]);
+        assert!(scheduler.tick(&at(&tz, "2024-07-05 06:29")).is_empty());
+        let due = scheduler.tick(&at(&tz, "2024-07-05 06:30"));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].payload, "b");
+        // A second tick in the same minute runs nothing
+        let later = at(&tz, "2024-07-05 06:30") + Duration::seconds(40);
+        assert!(scheduler.tick(&later).is_empty());
+        assert_eq!(scheduler.tick(&at(&tz, "2024-07-05 06:31"))[0].payload, "d");
+    }
+
+    #[test]
+    fn tick_skips_the_repeated_dst_hour() {
+        let tz = Tz::parse("Europe/Berlin").unwrap();
+        let mut scheduler = Scheduler::new(vec!["02:30 publish b".parse().unwrap()]);
+        // 02:30 CEST, then 02:30 CET an hour later
+        assert_eq!(scheduler.tick(&at(&tz, "2024-10-27 00:30")).len(), 1);
+        assert!(scheduler.tick(&at(&tz, "2024-10-27 01:00")).is_empty());
+        assert!(scheduler.tick(&at(&tz, "2024-10-27 01:30")).is_empty());
+        assert!(scheduler.tick(&at(&tz, "2024-10-27 01:31")).is_empty());
+        // Past the repeated hour it carries on
+        assert!(scheduler.tick(&at(&tz, "2024-10-27 02:01")).is_empty());
+        assert_eq!(scheduler.tick(&at(&tz, "2024-10-28 01:30")).len(), 1);
+    }
+
+    #[test]
+    fn tick_runs_again_after_the_clock_is_set_back() {
+        let tz = Tz::utc();
+        let mut scheduler = Scheduler::new(vec!["06:30 publish b".parse().unwrap()]);
+        // The RTC ran a day ahead
+        assert!(scheduler.tick(&at(&tz, "2024-07-06 12:00")).is_empty());
+        assert!(scheduler.tick(&at(&tz, "2024-07-05 06:29")).is_empty());
+        assert_eq!(scheduler.tick(&at(&tz, "2024-07-05 06:30")).len(), 1);
+    }
+
+    #[test]
+    fn next_picks_the_soonest() {
+        let scheduler = Scheduler::new(vec![
+            "22:00 publish b".parse().unwrap(),
+            "07:00 publish c".parse().unwrap(),
+        ]);
+        let (entry, run) = scheduler.next(&local("2024-07-05 21:00")).unwrap();
+        assert_eq!(entry.payload, "b");
+        assert_eq!(run, local("2024-07-05 22:00"));
+        let (entry, _) = scheduler.next(&local("2024-07-05 22:00")).unwrap();
+        assert_eq!(entry.payload, "c");
+    }
+}
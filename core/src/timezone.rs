@@ -0,0 +1,515 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, Utc, Weekday};
+
+// A handful of IANA names we are likely to see, mapped to their POSIX TZ rule.
+// Anything else has to be given as a POSIX TZ string directly.
+static IANA_ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("Etc/UTC", "UTC0"),
+    ("Asia/Ho_Chi_Minh", "<+07>-7"),
+    ("Asia/Saigon", "<+07>-7"),
+    ("Asia/Bangkok", "<+07>-7"),
+    ("Asia/Jakarta", "WIB-7"),
+    ("Asia/Singapore", "<+08>-8"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Hong_Kong", "HKT-8"),
+    ("Asia/Taipei", "CST-8"),
+    ("Asia/Seoul", "KST-9"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Dubai", "<+04>-4"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Melbourne", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Brisbane", "AEST-10"),
+    ("Australia/Perth", "AWST-8"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "IST-1GMT0,M10.5.0,M3.5.0/1"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Warsaw", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Moscow", "MSK-3"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Toronto", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Sao_Paulo", "<-03>3"),
+];
+
+// POSIX allows 0 to 24 hours for offsets. Transition times may go past a
+// day, as in Israel's `M3.4.4/26`, which RFC 8536 allows up to 167 hours.
+const MAX_OFFSET_HOURS: i32 = 24;
+const MAX_TRANSITION_HOURS: i32 = 167;
+
+/// Day within a year on which a DST transition happens, as described by POSIX TZ.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TransitionDay {
+    /// `Jn`: day 1..=365, February 29th is never counted
+    Julian1(u16),
+    /// `n`: day 0..=365, February 29th is counted in leap years
+    Julian0(u16),
+    /// `Mm.w.d`: weekday `d` of week `w` (5 = last) of month `m`
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Transition {
+    day: TransitionDay,
+    /// Local wall time of the transition, in seconds after midnight
+    time: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DstRule {
+    /// Seconds east of UTC while DST is in effect
+    offset: i32,
+    start: Transition,
+    end: Transition,
+}
+
+/// Timezone described by a POSIX TZ rule, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tz {
+    /// Seconds east of UTC for standard time
+    std_offset: i32,
+    dst: Option<DstRule>,
+}
+
+impl Tz {
+    pub fn utc() -> Tz {
+        Tz {
+            std_offset: 0,
+            dst: None,
+        }
+    }
+
+    /// Accepts an IANA zone name from the built-in table or a POSIX TZ string
+    pub fn parse(spec: &str) -> Result<Tz> {
+        let spec = spec.trim();
+        let posix = IANA_ZONES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(spec))
+            .map(|(_, posix)| *posix)
+            .unwrap_or(spec);
+
+        Parser::new(posix).parse_tz()
+    }
+
+    pub fn offset_at(&self, utc: &NaiveDateTime) -> FixedOffset {
+        let secs = match self.dst {
+            Some(dst) if self.in_dst(&dst, utc) => dst.offset,
+            _ => self.std_offset,
+        };
+        FixedOffset::east_opt(secs).unwrap()
+    }
+
+    pub fn to_local(&self, utc: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        utc.with_timezone(&self.offset_at(&utc.naive_utc()))
+    }
+
+    /// Map a local wall time back to UTC. Times repeated when DST ends resolve
+    /// to their first occurrence; times skipped when DST starts are taken as
+    /// if the clock had not jumped yet, so 02:30 becomes 03:30.
+    pub fn from_local(&self, local: &NaiveDateTime) -> DateTime<Utc> {
+        let mut offsets = vec![self.std_offset];
+        if let Some(dst) = self.dst {
+            offsets.push(dst.offset);
+        }
+
+        let valid = offsets
+            .iter()
+            .map(|offset| *local - Duration::seconds(*offset as i64))
+            .filter(|utc| {
+                let offset = self.offset_at(utc).local_minus_utc();
+                *utc + Duration::seconds(offset as i64) == *local
+            })
+            .min();
+
+        let utc = valid.unwrap_or_else(|| {
+            // In the gap, the offset from before the jump is the smaller one
+            let before = offsets.iter().min().copied().unwrap_or(self.std_offset);
+            *local - Duration::seconds(before as i64)
+        });
+        utc.and_utc()
+    }
+
+    fn in_dst(&self, dst: &DstRule, utc: &NaiveDateTime) -> bool {
+        let year = (*utc + Duration::seconds(self.std_offset as i64)).year();
+        // Start is given in standard time, end in daylight time
+        let start = transition_local(&dst.start, year) - Duration::seconds(self.std_offset as i64);
+        let end = transition_local(&dst.end, year) - Duration::seconds(dst.offset as i64);
+
+        if start < end {
+            // Northern hemisphere: DST in the middle of the year
+            *utc >= start && *utc < end
+        } else {
+            // Southern hemisphere: DST wraps around new year
+            *utc < end || *utc >= start
+        }
+    }
+}
+
+fn transition_local(transition: &Transition, year: i32) -> NaiveDateTime {
+    let date = match transition.day {
+        TransitionDay::Julian1(n) => {
+            // Day 60 is always March 1st, even in leap years
+            let mut ordinal = n as u32;
+            if is_leap_year(year) && ordinal >= 60 {
+                ordinal += 1;
+            }
+            NaiveDate::from_yo_opt(year, ordinal).unwrap()
+        }
+        TransitionDay::Julian0(n) => NaiveDate::from_yo_opt(year, n as u32 + 1)
+            .unwrap_or(NaiveDate::from_ymd_opt(year, 12, 31).unwrap()),
+        TransitionDay::MonthWeekDay {
+            month,
+            week,
+            weekday,
+        } => {
+            let weekday = Weekday::try_from(((weekday + 6) % 7) as u8).unwrap();
+            NaiveDate::from_weekday_of_month_opt(year, month, weekday, week as u8)
+                .or_else(|| NaiveDate::from_weekday_of_month_opt(year, month, weekday, 4))
+                .unwrap()
+        }
+    };
+
+    date.and_hms_opt(0, 0, 0).unwrap() + Duration::seconds(transition.time as i64)
+}
+
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Parser { input, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            bail!(
+                "Expected '{}' at {} in TZ \"{}\"",
+                c as char,
+                self.pos,
+                self.input
+            )
+        }
+    }
+
+    fn parse_tz(&mut self) -> Result<Tz> {
+        if self.input.is_empty() {
+            bail!("Empty timezone")
+        }
+        self.parse_name()?;
+        // POSIX offsets count hours west of UTC
+        let std_offset = -self.parse_offset(MAX_OFFSET_HOURS)?;
+        self.check_offset(std_offset)?;
+
+        if self.peek().is_none() {
+            return Ok(Tz {
+                std_offset,
+                dst: None,
+            });
+        }
+
+        self.parse_name()?;
+        let dst_offset = match self.peek() {
+            Some(b',') | None => std_offset + 3600,
+            _ => -self.parse_offset(MAX_OFFSET_HOURS)?,
+        };
+        self.check_offset(dst_offset)?;
+
+        // Default to the US rules, like glibc does, when the rule is omitted
+        let (start, end) = if self.eat(b',') {
+            let start = self.parse_transition()?;
+            self.expect(b',')?;
+            let end = self.parse_transition()?;
+            (start, end)
+        } else {
+            (
+                Transition {
+                    day: TransitionDay::MonthWeekDay {
+                        month: 3,
+                        week: 2,
+                        weekday: 0,
+                    },
+                    time: 7200,
+                },
+                Transition {
+                    day: TransitionDay::MonthWeekDay {
+                        month: 11,
+                        week: 1,
+                        weekday: 0,
+                    },
+                    time: 7200,
+                },
+            )
+        };
+
+        if self.peek().is_some() {
+            bail!("Unexpected trailing characters in TZ \"{}\"", self.input)
+        }
+
+        Ok(Tz {
+            std_offset,
+            dst: Some(DstRule {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    fn parse_name(&mut self) -> Result<()> {
+        let start = self.pos;
+        if self.eat(b'<') {
+            while let Some(c) = self.peek() {
+                self.pos += 1;
+                if c == b'>' {
+                    return Ok(());
+                }
+            }
+            bail!("Unterminated zone name in TZ \"{}\"", self.input)
+        }
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        if self.pos - start < 3 {
+            bail!("Zone name too short in TZ \"{}\"", self.input)
+        }
+        Ok(())
+    }
+
+    fn parse_number(&mut self) -> Result<i32> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.input[start..self.pos]
+            .parse()
+            .map_err(|_| anyhow!("Expected a number at {} in TZ \"{}\"", start, self.input))
+    }
+
+    /// Parses `[+|-]hh[:mm[:ss]]` into seconds
+    fn parse_offset(&mut self, max_hours: i32) -> Result<i32> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let hours = self.parse_number()?;
+        let (mut minutes, mut seconds) = (0, 0);
+        if self.eat(b':') {
+            minutes = self.parse_number()?;
+            if self.eat(b':') {
+                seconds = self.parse_number()?;
+            }
+        }
+        if hours > max_hours || minutes > 59 || seconds > 59 {
+            bail!("Time out of range in TZ \"{}\"", self.input)
+        }
+        Ok(sign * (hours * 3600 + minutes * 60 + seconds))
+    }
+
+    /// chrono only takes offsets of less than a day
+    fn check_offset(&self, offset: i32) -> Result<()> {
+        if offset.abs() >= 24 * 3600 {
+            bail!("Offset of a day or more in TZ \"{}\"", self.input)
+        }
+        Ok(())
+    }
+
+    fn parse_transition(&mut self) -> Result<Transition> {
+        let day = if self.eat(b'M') {
+            let month = self.parse_number()? as u32;
+            self.expect(b'.')?;
+            let week = self.parse_number()? as u32;
+            self.expect(b'.')?;
+            let weekday = self.parse_number()? as u32;
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                bail!("Invalid M rule in TZ \"{}\"", self.input)
+            }
+            TransitionDay::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else if self.eat(b'J') {
+            let n = self.parse_number()?;
+            if !(1..=365).contains(&n) {
+                bail!("Invalid J rule in TZ \"{}\"", self.input)
+            }
+            TransitionDay::Julian1(n as u16)
+        } else {
+            let n = self.parse_number()?;
+            if !(0..=365).contains(&n) {
+                bail!("Invalid day rule in TZ \"{}\"", self.input)
+            }
+            TransitionDay::Julian0(n as u16)
+        };
+
+        let time = if self.eat(b'/') {
+            self.parse_offset(MAX_TRANSITION_HOURS)?
+        } else {
+            7200
+        };
+
+        Ok(Transition { day, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn offset(tz: &Tz, utc: &str) -> i32 {
+        let utc = NaiveDateTime::parse_from_str(utc, "%Y-%m-%d %H:%M").unwrap();
+        tz.offset_at(&utc).local_minus_utc()
+    }
+
+    fn local(raw: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(raw, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn month_week_day_rule() {
+        let tz = Tz::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(offset(&tz, "2024-03-31 00:59"), 3600);
+        assert_eq!(offset(&tz, "2024-03-31 01:00"), 7200);
+        assert_eq!(offset(&tz, "2024-10-27 00:59"), 7200);
+        assert_eq!(offset(&tz, "2024-10-27 01:00"), 3600);
+    }
+
+    #[test]
+    fn julian_rules() {
+        // J60 is March 1st, also in leap years
+        let tz = Tz::parse("EST5EDT,J60,J300").unwrap();
+        assert_eq!(offset(&tz, "2024-03-01 06:59"), -5 * 3600);
+        assert_eq!(offset(&tz, "2024-03-01 07:00"), -4 * 3600);
+        // Zero based day 59 is February 29th in leap years
+        let tz = Tz::parse("EST5EDT,59,300").unwrap();
+        assert_eq!(offset(&tz, "2024-02-29 06:59"), -5 * 3600);
+        assert_eq!(offset(&tz, "2024-02-29 07:00"), -4 * 3600);
+        assert_eq!(offset(&tz, "2023-03-01 07:00"), -4 * 3600);
+    }
+
+    #[test]
+    fn default_rule_is_the_us_one() {
+        let tz = Tz::parse("EST5EDT").unwrap();
+        assert_eq!(offset(&tz, "2024-03-10 06:59"), -5 * 3600);
+        assert_eq!(offset(&tz, "2024-03-10 07:00"), -4 * 3600);
+    }
+
+    #[test]
+    fn southern_hemisphere() {
+        let tz = Tz::parse("Australia/Sydney").unwrap();
+        assert_eq!(offset(&tz, "2024-01-15 00:00"), 11 * 3600);
+        assert_eq!(offset(&tz, "2024-07-15 00:00"), 10 * 3600);
+        // DST starts at 02:00 standard time on the first Sunday of October
+        assert_eq!(offset(&tz, "2024-10-05 15:59"), 10 * 3600);
+        assert_eq!(offset(&tz, "2024-10-05 16:00"), 11 * 3600);
+        // and ends at 03:00 daylight time on the first Sunday of April
+        assert_eq!(offset(&tz, "2024-04-06 15:59"), 11 * 3600);
+        assert_eq!(offset(&tz, "2024-04-06 16:00"), 10 * 3600);
+    }
+
+    #[test]
+    fn iana_table() {
+        let tz = Tz::parse("asia/ho_chi_minh").unwrap();
+        assert_eq!(offset(&tz, "2024-07-01 00:00"), 7 * 3600);
+        let tz = Tz::parse(" Asia/Kolkata ").unwrap();
+        assert_eq!(offset(&tz, "2024-07-01 00:00"), 5 * 3600 + 1800);
+        let tz = Tz::parse("America/Sao_Paulo").unwrap();
+        assert_eq!(offset(&tz, "2024-07-01 00:00"), -3 * 3600);
+        for (name, posix) in IANA_ZONES {
+            assert!(Tz::parse(posix).is_ok(), "{} does not parse", name);
+        }
+    }
+
+    #[test]
+    fn local_times_around_transitions() {
+        let tz = Tz::parse("Europe/Berlin").unwrap();
+        let utc = |raw: &str| Utc.from_utc_datetime(&local(raw));
+        assert_eq!(
+            tz.from_local(&local("2024-07-01 12:00")),
+            utc("2024-07-01 10:00")
+        );
+        // Skipped, taken as if the clock had not jumped yet
+        assert_eq!(
+            tz.from_local(&local("2024-03-31 02:30")),
+            utc("2024-03-31 01:30")
+        );
+        // Repeated, the first occurrence wins
+        assert_eq!(
+            tz.from_local(&local("2024-10-27 02:30")),
+            utc("2024-10-27 00:30")
+        );
+    }
+
+    #[test]
+    fn transition_time_past_a_day() {
+        let tz = Tz::parse("IST-2IDT,M3.4.4/26,M10.5.0").unwrap();
+        // 02:00 on the Friday after the fourth Thursday of March
+        assert_eq!(offset(&tz, "2024-03-28 23:59"), 2 * 3600);
+        assert_eq!(offset(&tz, "2024-03-29 00:00"), 3 * 3600);
+    }
+
+    #[test]
+    fn invalid_input() {
+        for spec in [
+            "",
+            "EST",
+            "AB1",
+            "<+07-7",
+            "UTC99",
+            "UTC25",
+            "UTC-24",
+            "UTC1:60",
+            "UTC1:00:60",
+            "UTC99999999999",
+            "XYZ-24ABC",
+            "UTC0x",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.1.0,M10.5.0",
+            "CET-1CEST,M3.6.0,M10.5.0",
+            "CET-1CEST,M3.5.7,M10.5.0",
+            "CET-1CEST,J0,J300",
+            "CET-1CEST,366,300",
+            "CET-1CEST,M3.5.0/168,M10.5.0",
+            "Mars/Olympus_Mons",
+        ] {
+            assert!(Tz::parse(spec).is_err(), "{:?} parsed", spec);
+        }
+    }
+}
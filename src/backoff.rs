@@ -0,0 +1,23 @@
+const MIN_DELAY_MS: u32 = 1000;
+const MAX_DELAY_MS: u32 = 5 * 60 * 1000;
+
+/// Exponential backoff between reconnect attempts, doubling from a second up
+/// to five minutes
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn next_delay_ms(&mut self) -> u32 {
+        let delay = MIN_DELAY_MS
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_DELAY_MS);
+        self.attempts += 1;
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
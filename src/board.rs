@@ -0,0 +1,135 @@
+use crate::AppConfig;
+use anyhow::{bail, Result};
+use button_board_core::board::{parse_each, parse_pins, ActiveLevel, Board, ButtonPin, Edge, Pull};
+use esp_idf_svc::hal::gpio::{self, AnyIOPin, Input, InterruptType, PinDriver};
+use esp_idf_svc::sys::EspError;
+
+/// The profile named in the config, with the board_* settings that are set
+/// replacing its parts
+pub fn from_config(config: &AppConfig) -> Result<Board> {
+    let mut board = match config.board.trim() {
+        "" | "rev1" => Board::rev1(),
+        name => bail!("Unknown board \"{}\"", name),
+    };
+    if !config.board_buttons.is_empty() {
+        let pins: [u8; 8] = parse_pins(config.board_buttons)?;
+        for (button, gpio) in board.buttons.iter_mut().zip(pins) {
+            button.gpio = gpio;
+        }
+    }
+    if !config.board_i2c.is_empty() {
+        [board.sda, board.scl] = parse_pins(config.board_i2c)?;
+    }
+    if !config.board_sqw.is_empty() {
+        [board.sqw] = parse_pins(config.board_sqw)?;
+    }
+    if !config.board_button_pull.is_empty() {
+        let pulls = parse_each(config.board_button_pull)?;
+        for (button, pull) in board.buttons.iter_mut().zip(pulls) {
+            button.pull = pull;
+        }
+    }
+    if !config.board_button_active.is_empty() {
+        let levels = parse_each(config.board_button_active)?;
+        for (button, active) in board.buttons.iter_mut().zip(levels) {
+            button.active = active;
+        }
+    }
+    if !config.board_button_edge.is_empty() {
+        // A single value is meant for B to H, A keeps acting on the press
+        let skip = usize::from(!config.board_button_edge.contains(','));
+        let edges = parse_each(config.board_button_edge)?;
+        for (button, edge) in board.buttons.iter_mut().zip(edges).skip(skip) {
+            button.edge = edge;
+        }
+    }
+    board.validate()?;
+    Ok(board)
+}
+
+/// Button 0 to 7, A to H, set up to interrupt on its edge
+pub fn button(board: &Board, index: usize) -> Result<Button, EspError> {
+    Button::new(board.buttons[index])
+}
+
+/// The DS3231 INT/SQW input, which its alarms pull low
+pub fn sqw_input(board: &Board) -> Result<PinDriver<'static, AnyIOPin, Input>, EspError> {
+    // Safety: the board has been validated, so no other driver uses this pin
+    let mut pin = PinDriver::input(unsafe { AnyIOPin::new(board.sqw as i32) })?;
+    pin.set_interrupt_type(InterruptType::NegEdge)?;
+    Ok(pin)
+}
+
+/// SDA and SCL for the I2C driver
+pub fn i2c_pins(board: &Board) -> (AnyIOPin, AnyIOPin) {
+    // Safety: the board has been validated, so no other driver uses these pins
+    unsafe {
+        (
+            AnyIOPin::new(board.sda as i32),
+            AnyIOPin::new(board.scl as i32),
+        )
+    }
+}
+
+fn gpio_pull(pull: Pull) -> gpio::Pull {
+    match pull {
+        Pull::Floating => gpio::Pull::Floating,
+        Pull::Up => gpio::Pull::Up,
+        Pull::Down => gpio::Pull::Down,
+    }
+}
+
+fn interrupt_type(wiring: &ButtonPin) -> InterruptType {
+    let press = match wiring.active {
+        ActiveLevel::High => InterruptType::PosEdge,
+        ActiveLevel::Low => InterruptType::NegEdge,
+    };
+    let release = match wiring.active {
+        ActiveLevel::High => InterruptType::NegEdge,
+        ActiveLevel::Low => InterruptType::PosEdge,
+    };
+    match wiring.edge {
+        Edge::Press => press,
+        Edge::Release => release,
+    }
+}
+
+/// A button input set up as the board describes it
+pub struct Button {
+    pin: PinDriver<'static, AnyIOPin, Input>,
+    wiring: ButtonPin,
+}
+
+impl Button {
+    fn new(wiring: ButtonPin) -> Result<Self, EspError> {
+        // Safety: the board has been validated, so no other driver uses this pin
+        let mut pin = PinDriver::input(unsafe { AnyIOPin::new(wiring.gpio as i32) })?;
+        pin.set_pull(gpio_pull(wiring.pull))?;
+        pin.set_interrupt_type(interrupt_type(&wiring))?;
+        Ok(Button { pin, wiring })
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pin.is_high() == (self.wiring.active == ActiveLevel::High)
+    }
+
+    /// Whether an interrupt can be acted on now. Buttons acting on the
+    /// release wait until the button is let go.
+    pub fn is_ready(&self) -> bool {
+        self.wiring.edge == Edge::Press || !self.is_pressed()
+    }
+
+    /// # Safety
+    ///
+    /// See [`PinDriver::subscribe`]
+    pub unsafe fn subscribe(
+        &mut self,
+        callback: impl FnMut() + Send + 'static,
+    ) -> Result<(), EspError> {
+        self.pin.subscribe(callback)
+    }
+
+    pub fn enable_interrupt(&mut self) -> Result<(), EspError> {
+        self.pin.enable_interrupt()
+    }
+}
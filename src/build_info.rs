@@ -0,0 +1,27 @@
+use chrono::DateTime;
+
+/// Filled in by build.rs
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("BUILD_GIT_HASH");
+pub const FEATURES: &str = env!("BUILD_FEATURES");
+const BUILD_TIME: &str = env!("BUILD_TIME");
+
+/// Build time in UTC, e.g. "2024-09-30 14:05"
+pub fn build_time() -> String {
+    BUILD_TIME
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub fn summary() -> String {
+    format!(
+        "button-board {} ({}) built {} UTC, features: {}",
+        VERSION,
+        GIT_HASH,
+        build_time(),
+        FEATURES
+    )
+}
@@ -0,0 +1,250 @@
+use crate::drift::{self, Drift};
+use crate::error::{self, Error};
+use crate::storage::Storage;
+use crate::timezone::Tz;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+use ds323x::ic::DS3231;
+use ds323x::interface::I2cInterface;
+use ds323x::{Alarm1Matching, Alarm2Matching, DateTimeAccess, DayAlarm1, DayAlarm2, Ds323x, Hours};
+use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::sys::{settimeofday, timeval};
+use log::{info, warn};
+use shared_bus::{I2cProxy, NullMutex};
+
+// NVS key holding when the RTC was last set from NTP, as a unix timestamp
+const RTC_SET_KEY: &str = "rtc_set_at";
+
+pub type Rtc<'a> = Ds323x<I2cInterface<I2cProxy<'a, NullMutex<I2cDriver<'a>>>>, DS3231>;
+
+/// Time service on top of the DS3231. The RTC always holds UTC, the configured
+/// timezone is only applied when a local time is asked for. While the RTC does
+/// not answer, the system clock stands in for it.
+pub struct Clock<'a> {
+    rtc: Rtc<'a>,
+    tz: Tz,
+    responding: bool,
+    // Minute of the system clock the last fallback tick was for
+    last_minute: i64,
+}
+
+impl<'a> Clock<'a> {
+    pub fn new(rtc: Rtc<'a>, tz: Tz) -> Self {
+        Clock {
+            rtc,
+            tz,
+            responding: true,
+            last_minute: 0,
+        }
+    }
+
+    pub fn is_responding(&self) -> bool {
+        self.responding
+    }
+
+    pub fn tz(&self) -> &Tz {
+        &self.tz
+    }
+
+    pub fn set_tz(&mut self, tz: Tz) {
+        self.tz = tz;
+    }
+
+    pub fn now_utc(&mut self) -> Result<DateTime<Utc>, Error> {
+        let dt = error::retry(|| self.rtc.datetime())
+            .map_err(|e| Error::Rtc(format!("Cannot read RTC: {:?}", e)));
+        self.check(dt.as_ref().err());
+        Ok(dt?.and_utc())
+    }
+
+    /// The RTC time, or the system time if the RTC cannot be read
+    pub fn now(&mut self) -> DateTime<Utc> {
+        self.now_utc().unwrap_or_else(|_| Utc::now())
+    }
+
+    pub fn now_local(&mut self) -> DateTime<FixedOffset> {
+        let utc = self.now();
+        self.tz.to_local(&utc)
+    }
+
+    /// Make Alarm2 go off every minute. Also switches the INT/SQW pin to
+    /// alarms, so this sets the RTC up again after it lost power.
+    pub fn arm_minute_tick(&mut self) -> Result<(), Error> {
+        error::retry(|| {
+            self.rtc.use_int_sqw_output_as_interrupt()?;
+            self.rtc.enable_alarm2_interrupts()?;
+            self.rtc.clear_alarm2_matched_flag()?;
+            self.rtc.set_alarm2_day(
+                DayAlarm2 {
+                    day: 1,
+                    hour: Hours::H24(0),
+                    minute: 0,
+                },
+                Alarm2Matching::OncePerMinute,
+            )
+        })
+        .map_err(|e| Error::Rtc(format!("Cannot set minute alarm: {:?}", e)))
+    }
+
+    /// Whether a new minute started since the last call. Without the RTC the
+    /// system clock is watched instead.
+    pub fn take_minute_tick(&mut self) -> bool {
+        let matched = error::retry(|| self.rtc.has_alarm2_matched())
+            .map_err(|e| Error::Rtc(format!("Cannot read minute alarm: {:?}", e)));
+        let was_responding = self.responding;
+        self.check(matched.as_ref().err());
+        match matched {
+            Ok(matched) => {
+                if matched || !was_responding {
+                    if let Err(e) = self.arm_minute_tick() {
+                        error::record(e);
+                    }
+                }
+                matched
+            }
+            Err(_) => {
+                let minute = Utc::now().timestamp() / 60;
+                std::mem::replace(&mut self.last_minute, minute) != minute
+            }
+        }
+    }
+
+    /// Whether Alarm1 went off. The flag stays set until the next alarm is programmed.
+    pub fn alarm1_fired(&mut self) -> bool {
+        let matched = error::retry(|| self.rtc.has_alarm1_matched())
+            .map_err(|e| Error::Rtc(format!("Cannot read alarm: {:?}", e)));
+        self.check(matched.as_ref().err());
+        matched.unwrap_or(false)
+    }
+
+    /// Track whether the RTC answers. Only the first failure of a run is
+    /// recorded, a missing RTC would flood the log otherwise.
+    fn check(&mut self, failure: Option<&Error>) {
+        match failure {
+            Some(e) if self.responding => {
+                self.responding = false;
+                error::record(e.clone());
+            }
+            None if !self.responding => {
+                self.responding = true;
+                info!("RTC is responding again");
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_utc(&mut self, utc: &DateTime<Utc>) -> Result<()> {
+        self.rtc
+            .set_datetime(&utc.naive_utc())
+            .map_err(|e| anyhow!("Cannot write RTC: {:?}", e))?;
+        self.rtc
+            .clear_has_been_stopped_flag()
+            .map_err(|e| anyhow!("Cannot clear RTC stop flag: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Program Alarm1 to go off at `at`, or switch it off. The RTC holds UTC,
+    /// so matching on day of month, hour, minute and second is unambiguous.
+    pub fn set_alarm1(&mut self, at: Option<DateTime<Utc>>) -> Result<()> {
+        self.rtc
+            .clear_alarm1_matched_flag()
+            .map_err(|e| anyhow!("Cannot clear RTC alarm1 flag: {:?}", e))?;
+        match at {
+            Some(at) => {
+                self.rtc
+                    .set_alarm1_day(
+                        DayAlarm1 {
+                            day: at.day() as u8,
+                            hour: Hours::H24(at.hour() as u8),
+                            minute: at.minute() as u8,
+                            second: at.second() as u8,
+                        },
+                        Alarm1Matching::AllMatch,
+                    )
+                    .map_err(|e| anyhow!("Cannot set RTC alarm1: {:?}", e))?;
+                self.rtc
+                    .enable_alarm1_interrupts()
+                    .map_err(|e| anyhow!("Cannot enable RTC alarm1: {:?}", e))?;
+            }
+            None => {
+                self.rtc
+                    .disable_alarm1_interrupts()
+                    .map_err(|e| anyhow!("Cannot disable RTC alarm1: {:?}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the system time from the RTC so `Utc::now()` is usable before NTP
+    /// has synced. Skipped if the oscillator has stopped, as the RTC time is
+    /// garbage in that case.
+    pub fn seed_system_clock(&mut self) -> Result<()> {
+        let stopped = self
+            .rtc
+            .has_been_stopped()
+            .map_err(|e| anyhow!("Cannot read RTC status: {:?}", e))?;
+        if stopped {
+            warn!("RTC oscillator has been stopped, not seeding system clock");
+            return Ok(());
+        }
+
+        let now = self.now_utc()?;
+        let tv = timeval {
+            tv_sec: now.timestamp() as _,
+            tv_usec: 0,
+        };
+        unsafe {
+            settimeofday(&tv, std::ptr::null());
+        }
+        info!("System clock seeded from RTC: {}", now);
+
+        Ok(())
+    }
+
+    /// Compare the RTC against the freshly NTP-synced system time. The drift
+    /// since the RTC was last set is logged and, once measurable, folded into
+    /// the DS3231 aging offset. The RTC is only rewritten when it is off by more
+    /// than its resolution, so the drift can build up over a useful baseline.
+    pub fn sync_from_ntp(&mut self, storage: &mut Storage) -> Result<()> {
+        let ntp = Utc::now();
+        let rtc = self.now_utc()?;
+        let error_secs = rtc.timestamp() - ntp.timestamp();
+        let last_set = storage
+            .get_i64(RTC_SET_KEY)
+            .and_then(|ts| DateTime::from_timestamp(ts, 0));
+
+        match last_set {
+            Some(last_set) => {
+                let drift = Drift::new(error_secs, (ntp - last_set).num_seconds());
+                info!(
+                    "RTC is off by {}s from NTP, {}h after it was last set",
+                    drift.error_secs,
+                    drift.elapsed_secs / 3600
+                );
+                if let Some(ppm) = drift.ppm() {
+                    let current = self
+                        .rtc
+                        .aging_offset()
+                        .map_err(|e| anyhow!("Cannot read RTC aging offset: {:?}", e))?;
+                    let corrected = drift::corrected_aging_offset(current, ppm);
+                    info!(
+                        "RTC drift is {:.2} ppm, aging offset {} -> {}",
+                        ppm, current, corrected
+                    );
+                    self.rtc
+                        .set_aging_offset(corrected)
+                        .map_err(|e| anyhow!("Cannot write RTC aging offset: {:?}", e))?;
+                }
+            }
+            None => info!("RTC is off by {}s from NTP", error_secs),
+        }
+
+        if last_set.is_none() || error_secs.abs() >= drift::MAX_ERROR_SECS {
+            self.set_utc(&ntp)?;
+            storage.set_i64(RTC_SET_KEY, ntp.timestamp())?;
+            info!("RTC set to {}", ntp);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+use crate::alarm::Alarm;
+use crate::roaming::KnownNetwork;
+use crate::schedule::Schedule;
+use serde::Deserialize;
+
+/// Commands accepted on the control topic, e.g.
+/// `{"cmd": "alarm_add", "hour": 6, "minute": 30, "days": ["weekdays"], "label": "WAKE UP"}` or
+/// `{"cmd": "schedule_add", "entry": "weekdays 06:30 publish d"}`.
+/// Changes to the Wi-Fi networks are saved and used from the next restart.
+/// `{"cmd": "ota_update", "url": "https://...", "sha256": "..."}` installs new firmware.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    AlarmList,
+    AlarmAdd(Alarm),
+    AlarmRemove { index: usize },
+    AlarmEnable { index: usize, enabled: bool },
+    ScheduleList,
+    ScheduleAdd { entry: Schedule },
+    ScheduleRemove { index: usize },
+    Status,
+    WifiList,
+    WifiAdd(KnownNetwork),
+    WifiRemove { ssid: String },
+    OtaUpdate { url: String, sha256: String },
+}
+
+pub fn parse(raw: &[u8]) -> Result<Command, serde_json::Error> {
+    serde_json::from_slice(raw)
+}
@@ -0,0 +1,145 @@
+use crate::{device, mqtt, network};
+use esp_idf_svc::sys::{
+    esp_core_dump_get_summary, esp_core_dump_image_erase, esp_core_dump_summary_t,
+    esp_timer_get_time, ESP_OK,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fmt::{self, Write};
+use std::panic;
+use std::ptr::addr_of_mut;
+
+// Marks a record written by the panic hook, RTC memory holds garbage after power on
+const MAGIC: u32 = 0x6372_7368;
+const TEXT_LEN: usize = 200;
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    uptime_s: u32,
+    len: u32,
+    text: [u8; TEXT_LEN],
+}
+
+// Left alone by the restart after a panic, unlike the rest of RAM
+#[link_section = ".rtc_noinit"]
+static mut RECORD: Record = Record {
+    magic: 0,
+    uptime_s: 0,
+    len: 0,
+    text: [0; TEXT_LEN],
+};
+
+/// Why the last run ended, kept in NVS until it is published
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Crash {
+    pub reset_reason: String,
+    /// Panic message and where it was raised, only known for Rust panics
+    pub panic: Option<String>,
+    pub uptime_s: Option<u32>,
+    /// Task, program counter, return address and cause from the core dump the
+    /// panic handler writes to flash, for any panic, abort or watchdog reset.
+    /// Decode the addresses with `addr2line -e` on the matching firmware ELF.
+    pub backtrace: Option<String>,
+}
+
+/// Keep the message of a panic for the next boot. The default hook still
+/// prints it, then ESP-IDF restarts the chip.
+pub fn install_panic_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        save(info);
+        default(info);
+    }));
+}
+
+fn save(info: &impl fmt::Display) {
+    // Formatted into a fixed buffer, allocating may be what failed
+    let mut text = Text {
+        buf: [0; TEXT_LEN],
+        len: 0,
+    };
+    let _ = write!(text, "{}", info);
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    record.uptime_s = (unsafe { esp_timer_get_time() } / 1_000_000) as u32;
+    record.text = text.buf;
+    record.len = text.len as u32;
+    record.magic = MAGIC;
+}
+
+/// The crash that ended the last run, `None` after a clean restart
+pub fn take() -> Option<Crash> {
+    let reset_reason = device::reset_reason();
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    let panicked = reset_reason == "panic" && record.magic == MAGIC;
+    record.magic = 0;
+
+    let backtrace = backtrace();
+    let crashed = matches!(
+        reset_reason,
+        "panic" | "interrupt_watchdog" | "task_watchdog" | "watchdog" | "brownout"
+    );
+    if !crashed {
+        return None;
+    }
+    let len = (record.len as usize).min(TEXT_LEN);
+    Some(Crash {
+        reset_reason: reset_reason.to_string(),
+        panic: panicked.then(|| String::from_utf8_lossy(&record.text[..len]).into_owned()),
+        uptime_s: panicked.then_some(record.uptime_s),
+        backtrace,
+    })
+}
+
+/// Summary of the core dump in flash, which is erased so an older dump is
+/// not reported again for a later crash that did not write one
+fn backtrace() -> Option<String> {
+    let mut summary: Box<esp_core_dump_summary_t> = Box::new(unsafe { std::mem::zeroed() });
+    if unsafe { esp_core_dump_get_summary(summary.as_mut()) } != ESP_OK {
+        return None;
+    }
+    unsafe { esp_core_dump_image_erase() };
+    let task = unsafe { CStr::from_ptr(summary.exc_task.as_ptr()) }.to_string_lossy();
+    let info = &summary.ex_info;
+    Some(format!(
+        "task {} pc {:#010x} ra {:#010x} sp {:#010x} mcause {:#x} mtval {:#x}",
+        task, summary.exc_pc, info.ra, info.sp, info.mcause, info.mtval
+    ))
+}
+
+/// Publish a crash report retained, returning whether it went out
+pub fn publish(mqtt_client: &network::MqttClient, topic: &str, crash: &Crash) -> bool {
+    let payload = match serde_json::to_string(crash) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Cannot encode crash report: {}", e);
+            return false;
+        }
+    };
+    match mqtt_client.lock().unwrap().as_mut() {
+        Some(client) => match mqtt::send_retained(client, topic, &payload) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Cannot publish crash report: {}", e);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+/// Collects formatted text, dropping what does not fit
+struct Text {
+    buf: [u8; TEXT_LEN],
+    len: usize,
+}
+
+impl fmt::Write for Text {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(TEXT_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
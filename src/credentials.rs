@@ -0,0 +1,138 @@
+use crate::roaming::{Enterprise, KnownNetwork};
+use crate::AppConfig;
+use anyhow::{bail, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Wi-Fi networks and MQTT login, entered through the setup portal and kept
+/// in NVS. Boards that were never set up use the values from cfg.toml instead.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Credentials {
+    pub networks: Vec<KnownNetwork>,
+    pub mqtt_url: String,
+    pub mqtt_user: String,
+    pub mqtt_password: String,
+    pub mqtt_tls: MqttTls,
+}
+
+/// What the broker certificate is checked against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trust {
+    /// The ISRG Root X1 built into the firmware, for Let's Encrypt brokers
+    #[default]
+    Isrg,
+    /// The public CA bundle that comes with ESP-IDF
+    Bundle,
+    /// The PEM in [`MqttTls::ca_cert`], for brokers with a private CA
+    Custom,
+    /// The ESP-TLS global CA store, loaded with [`MqttTls::ca_cert`], for a
+    /// private CA that other TLS clients on the board share
+    Global,
+    /// No TLS, for plain `mqtt://` brokers
+    None,
+}
+
+impl FromStr for Trust {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "isrg" | "embedded" => Ok(Trust::Isrg),
+            "bundle" => Ok(Trust::Bundle),
+            "custom" => Ok(Trust::Custom),
+            "global" => Ok(Trust::Global),
+            "none" => Ok(Trust::None),
+            _ => bail!("Unknown MQTT trust \"{}\"", name),
+        }
+    }
+}
+
+/// TLS settings for the broker connection. A client certificate and key turn
+/// on mutual TLS.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttTls {
+    pub trust: Trust,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+}
+
+impl MqttTls {
+    pub fn validate(&self) -> Result<()> {
+        if matches!(self.trust, Trust::Custom | Trust::Global) && self.ca_cert.is_none() {
+            bail!("{:?} MQTT trust needs a CA certificate", self.trust)
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            bail!("MQTT client certificate and key go together")
+        }
+        Ok(())
+    }
+}
+
+impl Credentials {
+    /// Without a network to join the board needs the setup portal
+    pub fn is_provisioned(&self) -> bool {
+        !self.networks.is_empty()
+    }
+
+    /// The compiled in credentials, `None` if no Wi-Fi is configured
+    pub fn from_config(config: &AppConfig) -> Option<Self> {
+        if config.wifi_ssid.is_empty() {
+            return None;
+        }
+        let security = config.wifi_security.parse().unwrap_or_else(|e| {
+            error!("{}, using auto", e);
+            Default::default()
+        });
+        let enterprise = (!config.wifi_eap_username.is_empty()).then(|| Enterprise {
+            method: config.wifi_eap_method.parse().unwrap_or_else(|e| {
+                error!("{}, using PEAP", e);
+                Default::default()
+            }),
+            identity: config.wifi_eap_identity.to_string(),
+            username: config.wifi_eap_username.to_string(),
+            password: config.wifi_eap_password.to_string(),
+            ca_cert: (!config.wifi_eap_ca_cert.is_empty())
+                .then(|| config.wifi_eap_ca_cert.to_string()),
+        });
+        let bssid = match config.wifi_bssid {
+            "" => None,
+            raw => raw.parse().map_err(|e| error!("{}, not pinning", e)).ok(),
+        };
+
+        Some(Credentials {
+            networks: vec![KnownNetwork {
+                ssid: config.wifi_ssid.to_string(),
+                psk: config.wifi_psk.to_string(),
+                security,
+                enterprise,
+                hidden: config.wifi_hidden,
+                bssid,
+                ..Default::default()
+            }],
+            mqtt_url: config.mqtt_url.to_string(),
+            mqtt_user: config.mqtt_user.to_string(),
+            mqtt_password: config.mqtt_password.to_string(),
+            mqtt_tls: MqttTls {
+                trust: config.mqtt_tls.parse().unwrap_or_else(|e| {
+                    error!("{}, using the built in root", e);
+                    Default::default()
+                }),
+                ca_cert: pem(config.mqtt_ca_cert),
+                client_cert: pem(config.mqtt_client_cert),
+                client_key: pem(config.mqtt_client_key),
+            },
+        })
+    }
+}
+
+fn pem(raw: &str) -> Option<String> {
+    (!raw.is_empty()).then(|| raw.to_string())
+}
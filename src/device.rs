@@ -0,0 +1,56 @@
+use crate::ipconfig;
+use esp_idf_svc::sys::{
+    esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, esp_reset_reason,
+    esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+    esp_reset_reason_t_ESP_RST_EXT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_POWERON,
+    esp_reset_reason_t_ESP_RST_SDIO, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT,
+};
+use log::error;
+
+/// Station MAC address from eFuse, readable before Wi-Fi is started
+pub fn mac() -> [u8; 6] {
+    let mut mac = [0; 6];
+    unsafe {
+        esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA);
+    }
+    mac
+}
+
+/// Short id telling boards apart, the last three MAC bytes in hex
+pub fn id() -> String {
+    let mac = mac();
+    format!("{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+/// The configured hostname, or one derived from the device id so several
+/// boards on a network do not collide
+pub fn hostname(configured: &str) -> String {
+    if configured.is_empty() {
+        return format!("button-board-{}", id());
+    }
+    if !ipconfig::is_valid_hostname(configured) {
+        error!("Invalid hostname {}, using the default", configured);
+        return format!("button-board-{}", id());
+    }
+    configured.to_string()
+}
+
+/// Why the chip last started
+#[allow(non_upper_case_globals)]
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
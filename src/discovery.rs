@@ -0,0 +1,101 @@
+use crate::{build_info, device};
+use anyhow::Result;
+use esp_idf_svc::mdns::{EspMdns, Interface, Protocol, QueryResult};
+use log::{info, warn};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const SERVICE_TYPE: &str = "_button-board";
+const QUERY_TIMEOUT_MS: u64 = 3000;
+const MAX_RESULTS: usize = 4;
+
+/// Start mDNS. When `advertise` is set the board answers to `<hostname>.local`
+/// and announces itself as `_button-board._tcp` so it can be found without
+/// knowing its address. Nothing listens on the port, the board is driven over
+/// MQTT; the TXT record tells boards apart.
+pub fn start(hostname: &str, advertise: bool) -> Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    if !advertise {
+        return Ok(mdns);
+    }
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+    let id = device::id();
+    mdns.add_service(
+        None,
+        SERVICE_TYPE,
+        "_tcp",
+        0,
+        &[
+            ("id", &id),
+            ("version", build_info::VERSION),
+            ("git", build_info::GIT_HASH),
+        ],
+    )?;
+    info!("Advertising {}.local", hostname);
+
+    Ok(mdns)
+}
+
+/// Look for an MQTT broker on the local network, preferring one with TLS
+pub fn find_broker(mdns: &EspMdns) -> Option<String> {
+    for (service, scheme) in [("_secure-mqtt", "mqtts"), ("_mqtt", "mqtt")] {
+        match query(mdns, service) {
+            Ok(results) => {
+                if let Some(url) = results.iter().find_map(|r| broker_url(mdns, r, scheme)) {
+                    info!("Found MQTT broker {}", url);
+                    return Some(url);
+                }
+            }
+            Err(e) => warn!("Cannot query {}._tcp: {}", service, e),
+        }
+    }
+    None
+}
+
+fn query(mdns: &EspMdns, service: &str) -> Result<Vec<QueryResult>> {
+    let mut results = vec![
+        QueryResult {
+            instance_name: None,
+            hostname: None,
+            port: 0,
+            txt: Vec::new(),
+            addr: Vec::new(),
+            interface: Interface::STA,
+            ip_protocol: Protocol::V4,
+        };
+        MAX_RESULTS
+    ];
+    let found = mdns.query_ptr(
+        service,
+        "_tcp",
+        Duration::from_millis(QUERY_TIMEOUT_MS),
+        MAX_RESULTS,
+        &mut results,
+    )?;
+    results.truncate(found);
+
+    Ok(results)
+}
+
+/// The broker address as an URL. The IPv4 address is used rather than the
+/// `.local` name, which the regular resolver may not know.
+fn broker_url(mdns: &EspMdns, result: &QueryResult, scheme: &str) -> Option<String> {
+    if result.port == 0 {
+        return None;
+    }
+    let ip = result
+        .addr
+        .iter()
+        .find_map(|addr| match addr {
+            IpAddr::V4(ip) => Some(*ip),
+            IpAddr::V6(_) => None,
+        })
+        .or_else(|| {
+            let hostname = result.hostname.as_ref()?;
+            mdns.query_a(hostname, Duration::from_millis(QUERY_TIMEOUT_MS))
+                .ok()
+        })?;
+
+    Some(format!("{}://{}:{}", scheme, ip, result.port))
+}
@@ -0,0 +1,144 @@
+use crate::error::{self, Error};
+use embedded_hal::blocking::i2c::Write;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::i2c::I2cDriver;
+use hd44780_driver::bus::I2CBus;
+use hd44780_driver::{Cursor, CursorBlink, Display as Power, DisplayMode, HD44780};
+use log::{info, warn};
+use shared_bus::{BusManagerSimple, I2cProxy, NullMutex};
+use std::time::{Duration, Instant};
+
+/// I2C address of the PCF8574 behind the LCD
+const ADDRESS: u8 = 0x27;
+// Expander bit driving the backlight, enable and the data lines stay low
+const BACKLIGHT: u8 = 0x08;
+// How often a missing LCD is looked for again
+const DETECT_INTERVAL: Duration = Duration::from_secs(60);
+
+type Lcd<'a> = HD44780<I2CBus<I2cProxy<'a, NullMutex<I2cDriver<'a>>>>>;
+
+/// The 16x2 LCD. The board keeps working without it: drawing is skipped while
+/// it is missing, and it is picked up again once it answers. hd44780-driver
+/// ignores I2C errors, so whether the LCD is there is told by writing the
+/// expander directly before each redraw. One that comes back is set up from
+/// scratch, since it lost track of the 4-bit mode.
+pub struct Display<'a> {
+    bus: &'a BusManagerSimple<I2cDriver<'a>>,
+    lcd: Option<Lcd<'a>>,
+    // hd44780-driver always sets the backlight bit, so switching it off is done
+    // by writing the expander directly after each redraw. Also used to probe.
+    backlight: I2cProxy<'a, NullMutex<I2cDriver<'a>>>,
+    last_detect: Instant,
+}
+
+impl<'a> Display<'a> {
+    pub fn new(bus: &'a BusManagerSimple<I2cDriver<'a>>) -> Self {
+        let mut display = Display {
+            bus,
+            lcd: None,
+            backlight: bus.acquire_i2c(),
+            last_detect: Instant::now(),
+        };
+        display.detect();
+        if display.lcd.is_none() {
+            warn!("No LCD at {:#04x}, running without it", ADDRESS);
+        }
+        display
+    }
+
+    /// Clear the screen and show two lines
+    pub fn message(&mut self, line_1: &str, line_2: &str) {
+        self.draw(true, line_1, line_2);
+    }
+
+    /// Write two lines over what is on screen
+    pub fn show(&mut self, line_1: &str, line_2: &str) {
+        self.draw(false, line_1, line_2);
+    }
+
+    pub fn clear(&mut self) {
+        self.draw(true, "", "");
+    }
+
+    pub fn switch_backlight_off(&mut self) {
+        if self.lcd.is_none() {
+            return;
+        }
+        // Enable stays low, so the LCD ignores the write
+        if let Err(e) = error::retry(|| self.backlight.write(ADDRESS, &[0])) {
+            error::record(Error::I2c(format!("Cannot switch off backlight: {:?}", e)));
+        }
+    }
+
+    fn draw(&mut self, clear: bool, line_1: &str, line_2: &str) {
+        if self.lcd.is_none() && self.last_detect.elapsed() >= DETECT_INTERVAL {
+            self.detect();
+        }
+        if self.lcd.is_none() {
+            return;
+        }
+        if !self.probe() {
+            self.lcd = None;
+            self.last_detect = Instant::now();
+            error::record(Error::Lcd("Not responding, running without it".to_string()));
+            return;
+        }
+        if let Some(lcd) = self.lcd.as_mut() {
+            if let Err(e) = write(lcd, clear, line_1, line_2) {
+                error::record(Error::Lcd(format!("Write failed: {:?}", e)));
+            }
+        }
+    }
+
+    fn detect(&mut self) {
+        self.last_detect = Instant::now();
+        if !self.probe() {
+            return;
+        }
+        match setup(self.bus) {
+            Ok(lcd) => {
+                self.lcd = Some(lcd);
+                info!("LCD found");
+            }
+            Err(e) => error::record(Error::Lcd(format!("Cannot set up: {:?}", e))),
+        }
+    }
+
+    /// Whether the expander acknowledges its address
+    fn probe(&mut self) -> bool {
+        error::retry(|| self.backlight.write(ADDRESS, &[BACKLIGHT])).is_ok()
+    }
+}
+
+fn setup<'a>(
+    bus: &'a BusManagerSimple<I2cDriver<'a>>,
+) -> Result<Lcd<'a>, hd44780_driver::error::Error> {
+    let mut lcd = HD44780::new_i2c(bus.acquire_i2c(), ADDRESS, &mut FreeRtos)?;
+    lcd.reset(&mut FreeRtos)?;
+    lcd.clear(&mut FreeRtos)?;
+    lcd.set_display_mode(
+        DisplayMode {
+            display: Power::On,
+            cursor_visibility: Cursor::Invisible,
+            cursor_blink: CursorBlink::Off,
+        },
+        &mut FreeRtos,
+    )?;
+    Ok(lcd)
+}
+
+fn write(
+    lcd: &mut Lcd,
+    clear: bool,
+    line_1: &str,
+    line_2: &str,
+) -> Result<(), hd44780_driver::error::Error> {
+    if clear {
+        lcd.clear(&mut FreeRtos)?;
+    }
+    lcd.set_cursor_pos(0, &mut FreeRtos)?;
+    lcd.write_str(line_1, &mut FreeRtos)?;
+    lcd.set_cursor_pos(40, &mut FreeRtos)?;
+    lcd.write_str(line_2, &mut FreeRtos)?;
+    Ok(())
+}
@@ -0,0 +1,39 @@
+/// Shortest time since the RTC was last set for a drift measurement to mean anything
+const MIN_BASELINE_SECS: i64 = 2 * 24 * 3600;
+/// The DS3231 only counts whole seconds, so smaller errors are just noise
+pub const MAX_ERROR_SECS: i64 = 2;
+/// One LSB of the DS3231 aging offset is about 0.1 ppm at 25°C
+const PPM_PER_LSB: f32 = 0.1;
+/// Largest aging offset change per sync, so one bad sample can't throw the RTC off
+const MAX_STEP: i32 = 10;
+
+/// Difference between the RTC and NTP, measured since the RTC was last set
+#[derive(Clone, Copy, Debug)]
+pub struct Drift {
+    /// RTC minus NTP, positive when the RTC runs fast
+    pub error_secs: i64,
+    pub elapsed_secs: i64,
+}
+
+impl Drift {
+    pub fn new(error_secs: i64, elapsed_secs: i64) -> Self {
+        Drift {
+            error_secs,
+            elapsed_secs,
+        }
+    }
+
+    /// Drift rate in ppm, if the baseline is long and the error large enough to trust
+    pub fn ppm(&self) -> Option<f32> {
+        if self.elapsed_secs < MIN_BASELINE_SECS || self.error_secs.abs() < MAX_ERROR_SECS {
+            return None;
+        }
+        Some(self.error_secs as f32 / self.elapsed_secs as f32 * 1_000_000.0)
+    }
+}
+
+pub fn corrected_aging_offset(current: i8, drift_ppm: f32) -> i8 {
+    // A positive aging offset slows the oscillator down, so a fast RTC needs a larger value
+    let step = ((drift_ppm / PPM_PER_LSB).round() as i32).clamp(-MAX_STEP, MAX_STEP);
+    (current as i32 + step).clamp(i8::MIN as i32, i8::MAX as i32) as i8
+}
@@ -0,0 +1,158 @@
+use esp_idf_svc::hal::delay::{Ets, FreeRtos};
+use esp_idf_svc::sys::{
+    gpio_get_level, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD, gpio_set_direction, gpio_set_level,
+    i2c_mode_t_I2C_MODE_MASTER, i2c_reset_rx_fifo, i2c_reset_tx_fifo, i2c_set_pin,
+};
+use log::{error, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A glitch on the bus usually clears up within a few milliseconds
+const I2C_RETRIES: u32 = 3;
+const RETRY_DELAY_MS: u32 = 10;
+// A device holding SDA low lets go within nine clocks, half a 100kHz period each
+const CLEAR_CLOCKS: u32 = 9;
+const CLEAR_HALF_PERIOD_US: u32 = 5;
+// A device that keeps holding SDA is not worth taking the bus from the driver more often
+const CLEAR_INTERVAL: Duration = Duration::from_secs(10);
+// I2C_NUM_0, the only controller the board uses
+const I2C_PORT: i32 = 0;
+
+const KINDS: [&str; 5] = ["i2c", "rtc", "lcd", "wifi", "mqtt"];
+
+static COUNTS: [AtomicU32; 5] = [const { AtomicU32::new(0) }; 5];
+static LAST: Mutex<Option<String>> = Mutex::new(None);
+// SDA and SCL, for clearing the bus
+static I2C_PINS: Mutex<Option<(u8, u8)>> = Mutex::new(None);
+static LAST_CLEAR: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Failures the board keeps running through, by the part that failed
+#[derive(Clone, Debug)]
+pub enum Error {
+    I2c(String),
+    Rtc(String),
+    Lcd(String),
+    Wifi(String),
+    Mqtt(String),
+}
+
+impl Error {
+    fn index(&self) -> usize {
+        match self {
+            Error::I2c(_) => 0,
+            Error::Rtc(_) => 1,
+            Error::Lcd(_) => 2,
+            Error::Wifi(_) => 3,
+            Error::Mqtt(_) => 4,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (Error::I2c(message)
+        | Error::Rtc(message)
+        | Error::Lcd(message)
+        | Error::Wifi(message)
+        | Error::Mqtt(message)) = self;
+        write!(f, "{}: {}", KINDS[self.index()], message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Log an error and count it for telemetry
+pub fn record(error: Error) {
+    error!("{}", error);
+    COUNTS[error.index()].fetch_add(1, Ordering::SeqCst);
+    *LAST.lock().unwrap() = Some(error.to_string());
+}
+
+/// Errors since boot by kind
+pub fn counts() -> BTreeMap<&'static str, u32> {
+    KINDS
+        .into_iter()
+        .zip(COUNTS.iter().map(|count| count.load(Ordering::SeqCst)))
+        .collect()
+}
+
+pub fn last() -> Option<String> {
+    LAST.lock().unwrap().clone()
+}
+
+/// The pins of the I2C driver, so a stuck bus can be cleared
+pub fn set_i2c_pins(sda: u8, scl: u8) {
+    *I2C_PINS.lock().unwrap() = Some((sda, scl));
+}
+
+/// Run an I2C transfer again when it fails, as glitches are common on a long
+/// bus. When every retry fails and a device is holding SDA low, the bus is
+/// cleared and the transfer gets one last try. A device that is not fitted
+/// just does not answer, which leaves SDA high and the bus alone.
+pub fn retry<T, E>(mut transfer: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match transfer() {
+            Err(_) if attempt < I2C_RETRIES => {
+                attempt += 1;
+                FreeRtos::delay_ms(RETRY_DELAY_MS);
+            }
+            Err(e) => {
+                return if clear_i2c_bus() { transfer() } else { Err(e) };
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Clock SCL by hand until the device holding SDA lets go, then send a stop
+/// and hand the pins back to the I2C controller. Only done when SDA is low,
+/// at most once per [`CLEAR_INTERVAL`], returns whether it was.
+fn clear_i2c_bus() -> bool {
+    let Some((sda, scl)) = *I2C_PINS.lock().unwrap() else {
+        return false;
+    };
+    let (sda, scl) = (sda as i32, scl as i32);
+    // Safety: reading the input level leaves the pin with the driver
+    if unsafe { gpio_get_level(sda) } != 0 {
+        return false;
+    }
+    let mut last_clear = LAST_CLEAR.lock().unwrap();
+    if last_clear.is_some_and(|at| at.elapsed() < CLEAR_INTERVAL) {
+        return false;
+    }
+    *last_clear = Some(Instant::now());
+
+    // Safety: only called between transfers, the driver does not use the pins
+    unsafe {
+        gpio_set_direction(sda, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD);
+        gpio_set_direction(scl, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD);
+        gpio_set_level(sda, 1);
+        gpio_set_level(scl, 1);
+        for _ in 0..CLEAR_CLOCKS {
+            if gpio_get_level(sda) == 1 {
+                break;
+            }
+            gpio_set_level(scl, 0);
+            Ets::delay_us(CLEAR_HALF_PERIOD_US);
+            gpio_set_level(scl, 1);
+            Ets::delay_us(CLEAR_HALF_PERIOD_US);
+        }
+        // Stop: SDA rises while SCL is high
+        gpio_set_level(scl, 0);
+        gpio_set_level(sda, 0);
+        Ets::delay_us(CLEAR_HALF_PERIOD_US);
+        gpio_set_level(scl, 1);
+        Ets::delay_us(CLEAR_HALF_PERIOD_US);
+        gpio_set_level(sda, 1);
+
+        i2c_set_pin(I2C_PORT, sda, scl, true, true, i2c_mode_t_I2C_MODE_MASTER);
+        i2c_reset_tx_fifo(I2C_PORT);
+        i2c_reset_rx_fifo(I2C_PORT);
+    }
+    warn!("I2C bus cleared");
+    true
+}
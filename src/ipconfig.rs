@@ -0,0 +1,70 @@
+use anyhow::{anyhow, bail, Result};
+use std::net::Ipv4Addr;
+
+/// Fixed IPv4 settings used instead of DHCP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    /// Parse the config values, `None` when no address is set. The address is
+    /// written with its prefix length, e.g. "192.168.1.50/24".
+    pub fn parse(
+        address: &str,
+        gateway: &str,
+        dns: &str,
+        secondary_dns: &str,
+    ) -> Result<Option<StaticIp>> {
+        if address.is_empty() {
+            return Ok(None);
+        }
+        let (ip, prefix) = address.split_once('/').unwrap_or((address, "24"));
+        let ip = parse_ip(ip)?;
+        let prefix: u8 = prefix
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid prefix length in \"{}\"", address))?;
+        if prefix == 0 || prefix > 30 {
+            bail!("Invalid prefix length in \"{}\"", address)
+        }
+        let gateway = parse_ip(gateway)?;
+
+        Ok(Some(StaticIp {
+            ip,
+            prefix,
+            gateway,
+            dns: parse_optional_ip(dns)?,
+            secondary_dns: parse_optional_ip(secondary_dns)?,
+        }))
+    }
+}
+
+fn parse_ip(raw: &str) -> Result<Ipv4Addr> {
+    raw.trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid IPv4 address \"{}\"", raw))
+}
+
+fn parse_optional_ip(raw: &str) -> Result<Option<Ipv4Addr>> {
+    if raw.trim().is_empty() {
+        Ok(None)
+    } else {
+        parse_ip(raw).map(Some)
+    }
+}
+
+/// Letters, digits and inner hyphens, at most 30 characters as ESP-IDF allows
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 30
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
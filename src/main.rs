@@ -1,33 +1,71 @@
+mod backoff;
+mod board;
+mod build_info;
+mod clock;
+mod control;
+mod crash;
+mod credentials;
+mod device;
+mod discovery;
+mod display;
+mod drift;
+mod error;
+mod ipconfig;
 mod mqtt;
+mod network;
+mod ntp;
+mod ota;
+mod provisioning;
+mod roaming;
+mod settings;
+mod storage;
+mod telemetry;
+mod watchdog;
 mod wifi;
 
-use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, Timelike, Utc};
-use ds323x::ic::DS3231;
-use ds323x::interface::I2cInterface;
-use ds323x::{Alarm2Matching, DateTimeAccess, DayAlarm2, Ds323x, Hours};
+use actions::{ButtonAction, ACTIONS, BUTTON_NAMES};
+use alarm::{Alarm, AlarmClock, Weekdays};
+use anyhow::Context;
+use button_board_core::{actions, alarm, event, menu, schedule, timezone};
+use chrono::{Datelike, NaiveDateTime, Timelike, Weekday};
+use clock::Clock;
+use control::Command;
+use crash::Crash;
+use credentials::Credentials;
+use display::Display;
+use ds323x::Ds323x;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::delay;
-use esp_idf_svc::hal::delay::FreeRtos;
-use esp_idf_svc::hal::gpio::{InterruptType, PinDriver};
+use esp_idf_svc::hal::delay::{FreeRtos, TickType};
 use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_svc::hal::prelude::{Hertz, Peripherals};
+use esp_idf_svc::hal::reset;
 use esp_idf_svc::hal::task::notification::{Notification, Notifier};
-use esp_idf_svc::mqtt::client::EventPayload;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::sntp::{EspSntp, SyncStatus};
 use esp_idf_svc::sys::nvs_flash_init;
-use hd44780_driver::bus::I2CBus;
-use hd44780_driver::{Cursor, CursorBlink, Display, DisplayMode, HD44780};
+use event::{Encoder, Gesture, Message};
 use log::{error, info};
+use menu::{Key, Menu, Outcome};
+use schedule::{Schedule, Scheduler};
 use serde::Deserialize;
-use shared_bus::{I2cProxy, NullMutex};
+use serde_json::json;
+use settings::Settings;
+use std::net::Ipv4Addr;
 use std::num::NonZeroU32;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::Arc;
-use std::thread;
+use storage::Storage;
+use watchdog::Watchdog;
 
-const ADDRESS: u8 = 0x27;
-static THREAD_SIZE: usize = 6000;
+const ALARMS_KEY: &str = "alarms";
+const SCHEDULES_KEY: &str = "schedules";
+const SETTINGS_KEY: &str = "settings";
+const CREDENTIALS_KEY: &str = "credentials";
+const CRASH_KEY: &str = "crash";
+/// Holding A this long opens the menu
+const LONG_PRESS_MS: u32 = 1000;
+/// The menu closes by itself after this long without a key press
+const MENU_TIMEOUT_MS: u64 = 30000;
 static CURRENT_DISPLAY_STATE: AtomicU8 = AtomicU8::new(0);
 static BUTTON_A_NOTICE: AtomicBool = AtomicBool::new(false);
 static BUTTON_B_NOTICE: AtomicBool = AtomicBool::new(false);
@@ -48,16 +86,118 @@ pub struct AppConfig {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    #[default("auto")]
+    wifi_security: &'static str,
+    #[default(false)]
+    wifi_hidden: bool,
+    #[default("")]
+    wifi_bssid: &'static str,
+    #[default("peap")]
+    wifi_eap_method: &'static str,
+    #[default("")]
+    wifi_eap_identity: &'static str,
+    #[default("")]
+    wifi_eap_username: &'static str,
+    #[default("")]
+    wifi_eap_password: &'static str,
+    #[default("")]
+    wifi_eap_ca_cert: &'static str,
+    #[default("")]
+    hostname: &'static str,
+    // Fixed address with prefix length, e.g. "192.168.1.50/24", DHCP when empty
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    gateway: &'static str,
+    #[default("")]
+    dns: &'static str,
+    #[default("")]
+    secondary_dns: &'static str,
+    #[default(true)]
+    mdns: bool,
+    // Look for a broker over mDNS when mqtt_url is empty
+    #[default(true)]
+    mqtt_discovery: bool,
     #[default("")]
     mqtt_url: &'static str,
     #[default("")]
     mqtt_user: &'static str,
     #[default("")]
     mqtt_password: &'static str,
+    // isrg, bundle, custom, global or none. custom and global need mqtt_ca_cert
+    #[default("isrg")]
+    mqtt_tls: &'static str,
+    #[default("")]
+    mqtt_ca_cert: &'static str,
+    #[default("")]
+    mqtt_client_cert: &'static str,
+    #[default("")]
+    mqtt_client_key: &'static str,
+    // Retained online/offline, {device_id} is replaced with the end of the MAC
+    #[default("button-board/{device_id}/availability")]
+    mqtt_availability_topic: &'static str,
+    #[default(30)]
+    mqtt_keep_alive_secs: u32,
+    #[default(true)]
+    mqtt_clean_session: bool,
+    // Per button topic, e.g. "{prefix}/{device_id}/button/{name}/{gesture}",
+    // all buttons publish on mqtt_command_topic when empty
+    #[default("")]
+    mqtt_button_topic: &'static str,
+    // Topics for single actions, taking the place of the one above for them,
+    // e.g. "light_day=home/light/set,ac={prefix}/ac/{gesture}"
+    #[default("")]
+    mqtt_action_topics: &'static str,
+    #[default("button-board")]
+    mqtt_topic_prefix: &'static str,
+    // legacy for the single letter payloads, json for versioned events
+    #[default("legacy")]
+    mqtt_payload_format: &'static str,
     #[default("")]
     mqtt_room_topic: &'static str,
     #[default("")]
     mqtt_command_topic: &'static str,
+    #[default("")]
+    mqtt_control_topic: &'static str,
+    #[default("Asia/Ho_Chi_Minh")]
+    timezone: &'static str,
+    #[default(24)]
+    ntp_sync_interval_hours: u32,
+    // Pin map, rev1 is the original PCB. The board_* settings below replace
+    // parts of it, e.g. board_buttons = "18,19,20,21,22,23,2,3" for A to H
+    #[default("rev1")]
+    board: &'static str,
+    #[default("")]
+    board_buttons: &'static str,
+    // SDA and SCL, e.g. "6,7"
+    #[default("")]
+    board_i2c: &'static str,
+    #[default("")]
+    board_sqw: &'static str,
+    // The button settings take one value for all buttons, or eight comma
+    // separated ones for A to H. Pull is up, down or none
+    #[default("")]
+    board_button_pull: &'static str,
+    // Level while a button is held, high or low
+    #[default("")]
+    board_button_active: &'static str,
+    // When a button acts: press or release. A always acts on the press,
+    // a single value only sets B to H
+    #[default("")]
+    board_button_edge: &'static str,
+    // Retained board health, {device_id} is replaced with the end of the MAC
+    #[default("button-board/{device_id}/status")]
+    mqtt_status_topic: &'static str,
+    // 0 switches telemetry off
+    #[default(5)]
+    telemetry_interval_minutes: u32,
+    // A new firmware rolls back unless it reaches the broker within this time,
+    // 0 confirms it without waiting
+    #[default(300)]
+    ota_confirm_secs: u32,
+    // Retained report of the last crash, {device_id} is replaced with the end of the MAC
+    #[default("button-board/{device_id}/crash")]
+    mqtt_crash_topic: &'static str,
 }
 
 #[derive(Deserialize, Debug)]
@@ -77,8 +217,10 @@ fn main() -> anyhow::Result<()> {
 
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
+    crash::install_panic_hook();
 
     info!("Start application");
+    info!("{}", build_info::summary());
 
     // Load config
     let app_config: AppConfig = APP_CONFIG;
@@ -92,29 +234,52 @@ fn main() -> anyhow::Result<()> {
     // Needed for wifi
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    let mut storage = Storage::new(nvs.clone())?;
+    let mut settings: Settings = storage.load(SETTINGS_KEY).unwrap_or_default();
+
+    // Kept until published, the board may crash again before it reaches the broker
+    if let Some(crash) = crash::take() {
+        error!(
+            "Last run ended with {}: {} ({})",
+            crash.reset_reason,
+            crash.panic.as_deref().unwrap_or("no panic message"),
+            crash.backtrace.as_deref().unwrap_or("no core dump")
+        );
+        if let Err(e) = storage.save(CRASH_KEY, &crash) {
+            error!("Cannot save crash report: {}", e);
+        }
+    }
+    let mut crash_report: Option<Crash> = storage.load(CRASH_KEY);
+
+    // A timezone picked from the menu wins over the config
+    let timezone = settings.timezone.as_deref().unwrap_or(app_config.timezone);
+    let tz = match timezone::Tz::parse(timezone) {
+        Ok(tz) => tz,
+        Err(e) => {
+            error!("Invalid timezone {}: {}, falling back to UTC", timezone, e);
+            timezone::Tz::utc()
+        }
+    };
 
     // Create notification
     let notification = Notification::new();
     let notifier = notification.notifier();
 
-    let mut a = PinDriver::input(peripherals.pins.gpio18)?;
-    let mut b = PinDriver::input(peripherals.pins.gpio19)?;
-    let mut c = PinDriver::input(peripherals.pins.gpio20)?;
-    let mut d = PinDriver::input(peripherals.pins.gpio21)?;
-    let mut e = PinDriver::input(peripherals.pins.gpio22)?;
-    let mut f = PinDriver::input(peripherals.pins.gpio23)?;
-    let mut g = PinDriver::input(peripherals.pins.gpio2)?;
-    let mut h = PinDriver::input(peripherals.pins.gpio3)?;
-
-    // Assign interrupt button
-    a.set_interrupt_type(InterruptType::PosEdge)?;
-    b.set_interrupt_type(InterruptType::PosEdge)?;
-    c.set_interrupt_type(InterruptType::PosEdge)?;
-    d.set_interrupt_type(InterruptType::PosEdge)?;
-    e.set_interrupt_type(InterruptType::PosEdge)?;
-    f.set_interrupt_type(InterruptType::PosEdge)?;
-    g.set_interrupt_type(InterruptType::PosEdge)?;
-    h.set_interrupt_type(InterruptType::PosEdge)?;
+    // Driving pins the PCB does not wire that way could short them, so a bad
+    // pin map stops the board here. The display is not set up yet, its I2C
+    // pins come from the map.
+    let board = board::from_config(&app_config).context("Invalid pin map in cfg.toml")?;
+    info!("Board {}", board.name);
+
+    // Buttons interrupt when pressed
+    let mut a = board::button(&board, 0)?;
+    let mut b = board::button(&board, 1)?;
+    let mut c = board::button(&board, 2)?;
+    let mut d = board::button(&board, 3)?;
+    let mut e = board::button(&board, 4)?;
+    let mut f = board::button(&board, 5)?;
+    let mut g = board::button(&board, 6)?;
+    let mut h = board::button(&board, 7)?;
 
     // Create notifiers for each button
     let notifier_a = Arc::clone(&notifier);
@@ -139,261 +304,374 @@ fn main() -> anyhow::Result<()> {
     }
 
     // Init I2C
-    let sda = peripherals.pins.gpio6;
-    let scl = peripherals.pins.gpio7;
+    let (sda, scl) = board::i2c_pins(&board);
 
     let mut i2c_config = I2cConfig::new();
     i2c_config.baudrate = Hertz(100 * 1000); // 100kHz
     let i2c_driver = I2cDriver::new(peripherals.i2c0, sda, scl, &i2c_config)?;
+    error::set_i2c_pins(board.sda, board.scl);
     let bus = shared_bus::BusManagerSimple::new(i2c_driver);
 
     // Init RTC module
-    let mut rtc = Ds323x::new_ds3231(bus.acquire_i2c());
-    rtc.use_int_sqw_output_as_interrupt().unwrap();
-    rtc.enable_alarm2_interrupts().unwrap();
+    let rtc = Ds323x::new_ds3231(bus.acquire_i2c());
+    let mut clock = Clock::new(rtc, tz);
+    if let Err(e) = clock.seed_system_clock() {
+        error!("Cannot seed system clock: {}", e);
+    }
     // Init sqw input for ds3231
-    let mut sqw = PinDriver::input(peripherals.pins.gpio10)?;
-    sqw.set_interrupt_type(InterruptType::NegEdge)?;
+    let mut sqw = board::sqw_input(&board)?;
     let sqw_notifier = Arc::clone(&notifier);
     unsafe {
         sqw.subscribe(move || handle_sqw_notice(&sqw_notifier))?;
     }
 
-    // Init LCD module
-    let mut lcd = HD44780::new_i2c(bus.acquire_i2c(), ADDRESS, &mut FreeRtos).unwrap();
-    lcd.reset(&mut FreeRtos).unwrap();
-    lcd.clear(&mut FreeRtos).unwrap();
-    lcd.set_display_mode(
-        DisplayMode {
-            display: Display::On,
-            cursor_visibility: Cursor::Invisible,
-            cursor_blink: CursorBlink::Off,
-        },
-        &mut FreeRtos,
-    )
-    .unwrap();
-    display_message(&mut lcd, "CONNECT TO WIFI", "")?;
-
-    // Init wifi
-    let mut wifi = wifi::wifi(
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
+    // Init LCD module, the board carries on without it
+    let mut display = Display::new(&bus);
+    // Holding A and H at boot opens the setup portal, as does a board without a network
+    let credentials = load_credentials(&storage);
+    let provisioned = credentials
+        .as_ref()
+        .is_some_and(Credentials::is_provisioned);
+    if !provisioned || (a.is_pressed() && h.is_pressed()) {
+        display.message("WIFI SETUP", provisioning::AP_SSID);
+        provisioning::run(
+            peripherals.modem,
+            sys_loop.clone(),
+            nvs.clone(),
+            &credentials.unwrap_or_default(),
+            |credentials| storage.save(CREDENTIALS_KEY, credentials),
+        )?;
+        display.message("SAVED", "RESTARTING");
+        FreeRtos::delay_ms(2000);
+        reset::restart();
+    }
+
+    let payload_format = app_config.mqtt_payload_format.parse().unwrap_or_else(|e| {
+        error!("{}, using legacy payloads", e);
+        Default::default()
+    });
+    let status_topic = app_config
+        .mqtt_status_topic
+        .replace("{device_id}", &device::id());
+    let mut minutes_since_telemetry = 0;
+    let crash_topic = app_config
+        .mqtt_crash_topic
+        .replace("{device_id}", &device::id());
+    let mut encoder = Encoder::new(
+        payload_format,
+        &device::id(),
+        app_config.mqtt_command_topic,
+        app_config.mqtt_button_topic,
+        app_config.mqtt_topic_prefix,
+    );
+    if let Err(e) = encoder.set_action_topics(app_config.mqtt_action_topics) {
+        error!("{}, ignoring mqtt_action_topics", e);
+    }
+
+    // Commands from the control topic are handled on this thread, where the RTC and NVS live
+    let (command_tx, command_rx) = mpsc::channel::<Command>();
+    let command_notifier = Arc::clone(&notifier);
+    let control_topic = app_config.mqtt_control_topic;
+    let reply_topic = format!("{}/reply", control_topic);
+
+    // Network comes up in the background, the clock runs off the RTC meanwhile
+    let mqtt_client = network::start(
+        APP_CONFIG,
+        credentials,
         peripherals.modem,
         sys_loop.clone(),
         nvs,
-    )?;
-
-    display_message(&mut lcd, "SYNCHRONIZE NTP", "")?;
-    // Create Handle and Configure SNTP
-    let ntp = EspSntp::new_default()?;
-    for _i in 0..5 {
-        match ntp.get_sync_status() {
-            SyncStatus::Reset => {
-                info!("reset");
-                FreeRtos::delay_ms(2000);
-                continue;
-            }
-            SyncStatus::Completed => {
-                info!("complete");
-                let now = get_current_time();
-                let dt = NaiveDate::from_ymd_opt(now.year(), now.month(), now.day())
-                    .unwrap()
-                    .and_hms_opt(now.hour(), now.minute(), now.second())
-                    .unwrap();
-                rtc.set_datetime(&dt).unwrap();
-                break;
-            }
-            SyncStatus::InProgress => {
-                info!("In progress");
-                FreeRtos::delay_ms(1000);
-                continue;
-            }
-        }
-    }
-
-    // Subcribe to Mqtt
-    let (mut mqtt_client, mut conn) = mqtt::init(
-        app_config.mqtt_url,
-        "bb",
-        app_config.mqtt_user,
-        app_config.mqtt_password,
-    )
-    .unwrap();
-    // when set this code in another separated function, it will get delete after fn exit, so
-    // need to keep it in main fn at the moment
-    // TODO: move this logic to other module. Maybe async can help
-    thread::Builder::new()
-        .stack_size(THREAD_SIZE)
-        .spawn(move || {
-            info!("MQTT Listening for messages");
-            while let Ok(event) = conn.next() {
-                match event.payload() {
-                    EventPayload::Received { data, .. } => {
-                        let info = convert_event_data(data);
-                        if info.temp == 0.0 {
-                        } else {
-                            TEMP.store(info.temp as u32, Ordering::SeqCst);
-                        }
-                        if info.humid == 0.0 {
-                        } else {
-                            HUMID.store(info.humid as u32, Ordering::SeqCst);
-                        }
-                        PM2_5.store(info.pm2_5 as u32, Ordering::SeqCst);
-                        PM10.store(info.pm10 as u32, Ordering::SeqCst);
+        Arc::clone(&notifier),
+        move |topic, data| {
+            if !control_topic.is_empty() && topic == Some(control_topic) {
+                match control::parse(data) {
+                    Ok(command) => {
+                        let _ = command_tx.send(command);
+                        unsafe { command_notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
                     }
-                    _ => {}
+                    Err(e) => error!("Invalid command: {}", e),
                 }
+            } else {
+                handle_room_message(data);
             }
-            info!("Connection closed");
-        })?;
+        },
+    );
 
-    // This fn also block. Maybe async will help
-    mqtt::subscribes(&mut mqtt_client, app_config.mqtt_room_topic);
+    if let Err(e) = ota::confirm_when_healthy(app_config.ota_confirm_secs) {
+        error!("Cannot check the running image: {}", e);
+    }
 
-    handle_alarm_every_minute(&mut rtc);
-    // handle_alarm_ntp_sync(&mut rtc, &ntp);
+    if let Err(e) = clock.arm_minute_tick() {
+        error::record(e);
+    }
 
-    // FreeRtos::delay_ms(5000);
-    // sys_loop.subscribe::<WifiEvent, _>(move |wifi_event| {
-    //     log::info!("some kind of wifi event {:?}", wifi_event)
-    // })?;
+    let mut alarm_clock = AlarmClock::new(storage.load(ALARMS_KEY).unwrap_or_default());
+    reschedule_alarms(&mut clock, &mut alarm_clock);
+    let mut alarm_flash = false;
+
+    let mut scheduler = Scheduler::new(storage.load(SCHEDULES_KEY).unwrap_or_default());
+
+    let mut menu: Option<Menu> = None;
+
+    // From here on a hang restarts the board
+    let watchdog = Watchdog::watch_current_task();
 
     loop {
+        watchdog.feed();
         // enable_interrupt should also be called after each received notification from non-ISR context
-        sqw.enable_interrupt()?;
-        a.enable_interrupt()?;
-        b.enable_interrupt()?;
-        c.enable_interrupt()?;
-        d.enable_interrupt()?;
-        e.enable_interrupt()?;
-        f.enable_interrupt()?;
-        g.enable_interrupt()?;
-        h.enable_interrupt()?;
+        for (pin, result) in [
+            ("SQW", sqw.enable_interrupt()),
+            ("A", a.enable_interrupt()),
+            ("B", b.enable_interrupt()),
+            ("C", c.enable_interrupt()),
+            ("D", d.enable_interrupt()),
+            ("E", e.enable_interrupt()),
+            ("F", f.enable_interrupt()),
+            ("G", g.enable_interrupt()),
+            ("H", h.enable_interrupt()),
+        ] {
+            if let Err(err) = result {
+                error!("Cannot enable interrupt of {}: {}", pin, err);
+            }
+        }
 
         // Re-draw display after every minute
-        if CURRENT_DISPLAY_STATE.load(Ordering::SeqCst) == 0 {
+        if let Some(percent) = ota::progress() {
+            display.message("UPDATING", &format!("{}%", percent));
+        } else if let Some((_, alarm)) = alarm_clock.ringing() {
+            alarm_flash = !alarm_flash;
+            display_alarm(&mut display, &alarm.label, alarm_flash);
+        } else if let Some(menu) = &menu {
+            let (line_1, line_2) = menu.render(&alarm_rows(&alarm_clock));
+            display.message(&line_1, &line_2);
+        } else if CURRENT_DISPLAY_STATE.load(Ordering::SeqCst) == 0 {
             display_clock(
-                &mut lcd,
-                rtc.datetime().unwrap(),
+                &mut display,
+                clock.now_local().naive_local(),
                 TEMP.load(Ordering::SeqCst),
                 HUMID.load(Ordering::SeqCst),
-            )?;
+                ntp::is_synced(),
+                clock.is_responding(),
+                network::link_state(),
+            );
         } else if CURRENT_DISPLAY_STATE.load(Ordering::SeqCst) == 1 {
             display_aqi(
-                &mut lcd,
+                &mut display,
                 PM2_5.load(Ordering::SeqCst),
                 PM10.load(Ordering::SeqCst),
-            )?
+            )
+        } else if CURRENT_DISPLAY_STATE.load(Ordering::SeqCst) == 2 {
+            let now = clock.now_local().naive_local();
+            display_schedules(
+                &mut display,
+                scheduler.schedules().len(),
+                scheduler.next(&now),
+            )
+        } else if CURRENT_DISPLAY_STATE.load(Ordering::SeqCst) == 3 {
+            display_network(
+                &mut display,
+                &network::hostname(),
+                network::ip(),
+                network::link_state(),
+            )
+        }
+
+        // A ringing alarm always lights up the display
+        if !settings.backlight && alarm_clock.ringing().is_none() {
+            display.switch_backlight_off();
         }
 
-        notification.wait(delay::BLOCK);
+        // Wake up regularly while an alarm rings to flash the banner or an update runs
+        if alarm_clock.ringing().is_some() || ota::progress().is_some() {
+            notification.wait(TickType::new_millis(500).ticks());
+        } else if menu.is_some() {
+            if notification
+                .wait(TickType::new_millis(MENU_TIMEOUT_MS).ticks())
+                .is_none()
+            {
+                menu = None;
+                display.clear();
+            }
+        } else {
+            // Also keeps the clock going off the system time while the RTC is missing
+            notification.wait(TickType::new_millis(watchdog::FEED_INTERVAL_MS as u64).ticks());
+        }
 
         FreeRtos::delay_ms(100);
 
-        if rtc.has_alarm2_matched().unwrap() {
-            handle_alarm_every_minute(&mut rtc);
+        if clock.take_minute_tick() {
+            if app_config.telemetry_interval_minutes > 0 {
+                minutes_since_telemetry += 1;
+                if minutes_since_telemetry >= app_config.telemetry_interval_minutes {
+                    minutes_since_telemetry = 0;
+                    telemetry::publish(&mqtt_client, &status_topic);
+                }
+            }
+
+            let now = clock.now_local();
+            for schedule in scheduler.tick(&now) {
+                info!("Running schedule {}", schedule);
+                match encoder.encode_schedule(&schedule.payload, now.timestamp()) {
+                    Ok(message) => send_command(&mqtt_client, &message.topic, &message.payload),
+                    Err(e) => error!("Cannot send schedule {}: {:?}", schedule, e),
+                }
+            }
+        }
+        if let Some(crash) = &crash_report {
+            if network::mqtt_connected() && crash::publish(&mqtt_client, &crash_topic, crash) {
+                if let Err(e) = storage.remove(CRASH_KEY) {
+                    error!("Cannot clear crash report: {}", e);
+                }
+                crash_report = None;
+            }
+        }
+        if ntp::take_sync_pending() {
+            sync_rtc_from_ntp(&mut clock, &mut storage);
+            // The RTC may have jumped past the programmed alarm
+            reschedule_alarms(&mut clock, &mut alarm_clock);
+        }
+        if clock.alarm1_fired() {
+            let now = clock.now();
+            if alarm_clock.on_fire(&now) {
+                alarm_flash = false;
+            }
+            reschedule_alarms(&mut clock, &mut alarm_clock);
+        }
+        handle_commands(
+            &command_rx,
+            &mut alarm_clock,
+            &mut scheduler,
+            &mut clock,
+            &mut storage,
+            &mqtt_client,
+            &reply_topic,
+        );
+        if alarm_clock.ringing().is_some() {
+            // While ringing, A dismisses and any other button snoozes
+            let now = clock.now();
+            let dismiss = BUTTON_A_NOTICE.swap(false, Ordering::SeqCst);
+            let snooze = take_button_notices();
+            if dismiss || alarm_clock.timed_out(&now) {
+                if alarm_clock.dismiss() {
+                    save_alarms(&mut storage, &alarm_clock);
+                }
+            } else if snooze {
+                alarm_clock.snooze(&now);
+            }
+            if alarm_clock.ringing().is_none() {
+                display.clear();
+                reschedule_alarms(&mut clock, &mut alarm_clock);
+            }
         }
-        if BUTTON_A_NOTICE.load(Ordering::SeqCst) && a.is_low() {
+        if let Some(current) = menu.as_mut() {
+            let key = [
+                &BUTTON_A_NOTICE,
+                &BUTTON_B_NOTICE,
+                &BUTTON_C_NOTICE,
+                &BUTTON_D_NOTICE,
+            ]
+            .iter()
+            .position(|notice| notice.swap(false, Ordering::SeqCst))
+            .and_then(Key::for_button);
+            take_button_notices();
+
+            if let Some(key) = key {
+                match current.handle(key, &alarm_rows(&alarm_clock)) {
+                    Outcome::Stay => {}
+                    Outcome::Exit => {
+                        menu = None;
+                        display.clear();
+                    }
+                    Outcome::Run(action) => run_menu_action(
+                        action,
+                        current,
+                        &mut clock,
+                        &mut alarm_clock,
+                        &mut storage,
+                        &mut settings,
+                    ),
+                }
+            }
+            continue;
+        }
+        if BUTTON_A_NOTICE.load(Ordering::SeqCst) && a.is_pressed() {
+            // Still held after the debounce delay, a long press opens the menu
+            let mut held_ms = 100;
+            while a.is_pressed() && held_ms < LONG_PRESS_MS {
+                FreeRtos::delay_ms(50);
+                held_ms += 50;
+            }
+            if held_ms >= LONG_PRESS_MS {
+                BUTTON_A_NOTICE.store(false, Ordering::SeqCst);
+                take_button_notices();
+                menu = Some(Menu::new());
+                continue;
+            }
+        }
+        if BUTTON_A_NOTICE.load(Ordering::SeqCst) && !a.is_pressed() {
             // TODO: display function should have full line message so don't have to clear everytime
-            lcd.clear(&mut FreeRtos).unwrap();
+            display.clear();
             let state = CURRENT_DISPLAY_STATE.load(Ordering::SeqCst);
             if state == 0 {
                 CURRENT_DISPLAY_STATE.store(1, Ordering::SeqCst);
+            } else if state == 1 {
+                CURRENT_DISPLAY_STATE.store(2, Ordering::SeqCst);
+            } else if state == 2 {
+                CURRENT_DISPLAY_STATE.store(3, Ordering::SeqCst);
             } else {
                 CURRENT_DISPLAY_STATE.store(0, Ordering::SeqCst);
             }
             BUTTON_A_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_B_NOTICE.load(Ordering::SeqCst) && b.is_low() {
-            display_message(&mut lcd, "TURN ON/OFF AC", "")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "b");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_B_NOTICE.load(Ordering::SeqCst) && b.is_ready() {
+            let action = actions::assigned(&settings.buttons, 0);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(0, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_B_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_C_NOTICE.load(Ordering::SeqCst) && c.is_low() {
-            display_message(&mut lcd, "TURN ON/OFF", "   AIR FILTER")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "c");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_C_NOTICE.load(Ordering::SeqCst) && c.is_ready() {
+            let action = actions::assigned(&settings.buttons, 1);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(1, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_C_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_D_NOTICE.load(Ordering::SeqCst) && d.is_low() {
-            display_message(&mut lcd, "LIGHT MODE", "   DAY")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "d");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_D_NOTICE.load(Ordering::SeqCst) && d.is_ready() {
+            let action = actions::assigned(&settings.buttons, 2);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(2, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_D_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_E_NOTICE.load(Ordering::SeqCst) && e.is_low() {
-            display_message(&mut lcd, "LIGHT MODE", "  NIGHT")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "e");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_E_NOTICE.load(Ordering::SeqCst) && e.is_ready() {
+            let action = actions::assigned(&settings.buttons, 3);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(3, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_E_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_F_NOTICE.load(Ordering::SeqCst) && f.is_low() {
-            display_message(&mut lcd, "TURN ON/OFF LIGHT", "")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "f");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_F_NOTICE.load(Ordering::SeqCst) && f.is_ready() {
+            let action = actions::assigned(&settings.buttons, 4);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(4, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_F_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_G_NOTICE.load(Ordering::SeqCst) && g.is_low() {
-            display_message(&mut lcd, "EMPTY FUNCTION", "")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "g");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_G_NOTICE.load(Ordering::SeqCst) && g.is_ready() {
+            let action = actions::assigned(&settings.buttons, 5);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(5, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_G_NOTICE.store(false, Ordering::SeqCst);
         }
-        if BUTTON_H_NOTICE.load(Ordering::SeqCst) && h.is_low() {
-            display_message(&mut lcd, "EMPTY FUNCTION", "")?;
-            let result = mqtt::send_payload(&mut mqtt_client, app_config.mqtt_command_topic, "h");
-            match result {
-                Ok(_) => {}
-                Err(_) => {
-                    error!("cannot send command")
-                }
-            }
-            FreeRtos::delay_ms(1000);
+        if BUTTON_H_NOTICE.load(Ordering::SeqCst) && h.is_ready() {
+            let action = actions::assigned(&settings.buttons, 6);
+            let ts = clock.now().timestamp();
+            let message = encoder.encode(6, Gesture::Press, action, ts);
+            run_button_action(&mut display, &mqtt_client, action, message);
             BUTTON_H_NOTICE.store(false, Ordering::SeqCst);
         }
-
-        if !wifi.is_connected()? {
-            info!("wifi is down. reconnecting");
-            wifi.connect()?;
-            FreeRtos::delay_ms(5000);
-        }
     }
 }
 
@@ -430,16 +708,341 @@ fn handle_button_default(notifier: &Arc<Notifier>, button: &str) {
     unsafe { notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
 }
 
+/// Clear the pending notices of every button but A, returning whether any was set
+fn take_button_notices() -> bool {
+    [
+        &BUTTON_B_NOTICE,
+        &BUTTON_C_NOTICE,
+        &BUTTON_D_NOTICE,
+        &BUTTON_E_NOTICE,
+        &BUTTON_F_NOTICE,
+        &BUTTON_G_NOTICE,
+        &BUTTON_H_NOTICE,
+    ]
+    .iter()
+    .fold(false, |any, notice| {
+        notice.swap(false, Ordering::SeqCst) || any
+    })
+}
+
 fn handle_sqw_notice(notifier: &Arc<Notifier>) {
     unsafe { notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
 }
 
-fn get_current_time() -> DateTime<FixedOffset> {
-    let vn_offset = FixedOffset::east_opt(7 * 3600).unwrap();
-    // Obtain System Time
-    let now = Utc::now().with_timezone(&vn_offset);
-    // Print Time
-    now
+fn run_button_action(
+    display: &mut Display,
+    mqtt_client: &network::MqttClient,
+    action: &ButtonAction,
+    message: anyhow::Result<Message>,
+) {
+    display.message(action.line_1, action.line_2);
+    match message {
+        Ok(message) => send_command(mqtt_client, &message.topic, &message.payload),
+        Err(e) => error!("Cannot encode the {} event: {:?}", action.id, e),
+    }
+    FreeRtos::delay_ms(1000);
+}
+
+fn send_command(mqtt_client: &network::MqttClient, topic: &str, payload: &str) {
+    match mqtt_client.lock().unwrap().as_mut() {
+        Some(client) => {
+            if mqtt::send_payload(client, topic, payload).is_err() {
+                error!("cannot send command")
+            }
+        }
+        None => error!("MQTT is not connected, dropping command {}", payload),
+    }
+}
+
+fn handle_room_message(data: &[u8]) {
+    let info = convert_event_data(data);
+    if info.temp == 0.0 {
+    } else {
+        TEMP.store(info.temp as u32, Ordering::SeqCst);
+    }
+    if info.humid == 0.0 {
+    } else {
+        HUMID.store(info.humid as u32, Ordering::SeqCst);
+    }
+    PM2_5.store(info.pm2_5 as u32, Ordering::SeqCst);
+    PM10.store(info.pm10 as u32, Ordering::SeqCst);
+}
+
+fn reschedule_alarms(clock: &mut Clock, alarm_clock: &mut AlarmClock) {
+    let now = match clock.now_utc() {
+        Ok(now) => now,
+        Err(e) => {
+            error!("Cannot schedule alarms: {}", e);
+            return;
+        }
+    };
+    let next = alarm_clock.schedule(&now, clock.tz());
+    match next {
+        Some(at) => info!("Next alarm at {}", clock.tz().to_local(&at)),
+        None => info!("No alarm scheduled"),
+    }
+    if let Err(e) = clock.set_alarm1(next) {
+        error!("Cannot schedule alarms: {}", e);
+    }
+}
+
+fn save_alarms(storage: &mut Storage, alarm_clock: &AlarmClock) {
+    if let Err(e) = storage.save(ALARMS_KEY, &alarm_clock.alarms()) {
+        error!("Cannot save alarms: {}", e);
+    }
+}
+
+fn save_schedules(storage: &mut Storage, scheduler: &Scheduler) {
+    if let Err(e) = storage.save(SCHEDULES_KEY, &scheduler.schedules()) {
+        error!("Cannot save schedules: {}", e);
+    }
+}
+
+fn handle_commands(
+    commands: &Receiver<Command>,
+    alarm_clock: &mut AlarmClock,
+    scheduler: &mut Scheduler,
+    clock: &mut Clock,
+    storage: &mut Storage,
+    mqtt_client: &network::MqttClient,
+    reply_topic: &str,
+) {
+    while let Ok(command) = commands.try_recv() {
+        info!("Handling command {:?}", command);
+        let alarms_changed = matches!(
+            command,
+            Command::AlarmAdd(_) | Command::AlarmRemove { .. } | Command::AlarmEnable { .. }
+        );
+        let schedules_changed = matches!(
+            command,
+            Command::ScheduleAdd { .. } | Command::ScheduleRemove { .. }
+        );
+        let result = match command {
+            Command::AlarmList => Ok(json!({ "alarms": alarm_clock.alarms() })),
+            Command::AlarmAdd(alarm) => alarm_clock.add(alarm).map(|_| json!({ "ok": true })),
+            Command::AlarmRemove { index } => {
+                alarm_clock.remove(index).map(|_| json!({ "ok": true }))
+            }
+            Command::AlarmEnable { index, enabled } => alarm_clock
+                .set_enabled(index, enabled)
+                .map(|_| json!({ "ok": true })),
+            Command::ScheduleList => Ok(json!({ "schedules": scheduler.schedules() })),
+            Command::ScheduleAdd { entry } => {
+                scheduler.add(entry);
+                Ok(json!({ "ok": true }))
+            }
+            Command::ScheduleRemove { index } => {
+                scheduler.remove(index).map(|_| json!({ "ok": true }))
+            }
+            Command::Status => Ok(json!({
+                "version": build_info::VERSION,
+                "git_hash": build_info::GIT_HASH,
+                "wifi": network::link_state().name(),
+                "ssid": network::ssid(),
+                "hostname": network::hostname(),
+                "static_ip": !APP_CONFIG.static_ip.is_empty(),
+                "ip": network::ip().map(|ip| ip.to_string()),
+                "reconnects": network::reconnects(),
+                "mqtt": network::mqtt_connected(),
+                "ntp_synced": ntp::is_synced(),
+            })),
+            Command::WifiList => {
+                let credentials = load_credentials(storage).unwrap_or_default();
+                let networks: Vec<_> = credentials
+                    .networks
+                    .iter()
+                    .map(|n| {
+                        json!({
+                            "ssid": n.ssid,
+                            "priority": n.priority,
+                            "security": n.security,
+                            "hidden": n.hidden,
+                            "bssid": n.bssid,
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "networks": networks }))
+            }
+            Command::WifiAdd(network) => network.validate().and_then(|_| {
+                let mut credentials = load_credentials(storage).unwrap_or_default();
+                roaming::remember(&mut credentials.networks, network);
+                storage
+                    .save(CREDENTIALS_KEY, &credentials)
+                    .map(|_| json!({ "ok": true }))
+            }),
+            Command::WifiRemove { ssid } => {
+                let mut credentials = load_credentials(storage).unwrap_or_default();
+                let count = credentials.networks.len();
+                credentials.networks.retain(|n| n.ssid != ssid);
+                if credentials.networks.len() == count {
+                    Err(anyhow::anyhow!("Unknown network {}", ssid))
+                } else if !credentials.is_provisioned() {
+                    Err(anyhow::anyhow!(
+                        "{} is the last network, change it in the setup portal",
+                        ssid
+                    ))
+                } else {
+                    storage
+                        .save(CREDENTIALS_KEY, &credentials)
+                        .map(|_| json!({ "ok": true }))
+                }
+            }
+            Command::OtaUpdate { url, sha256 } => {
+                ota::start(url, &sha256).map(|_| json!({ "ok": true, "updating": true }))
+            }
+        };
+        let reply = match result {
+            Ok(reply) => {
+                if alarms_changed {
+                    save_alarms(storage, alarm_clock);
+                    reschedule_alarms(clock, alarm_clock);
+                }
+                if schedules_changed {
+                    save_schedules(storage, scheduler);
+                }
+                reply
+            }
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+        send_command(mqtt_client, reply_topic, &reply.to_string());
+    }
+}
+
+/// One line per alarm for the menu, e.g. "* 06:30 MTWTF--" where * marks it enabled
+fn alarm_rows(alarm_clock: &AlarmClock) -> Vec<String> {
+    alarm_clock
+        .alarms()
+        .iter()
+        .map(|alarm| {
+            let days = if alarm.days.is_empty() {
+                "ONCE".to_string()
+            } else {
+                alarm.days.letters()
+            };
+            let enabled = if alarm.enabled { '*' } else { ' ' };
+            format!("{} {:02}:{:02} {}", enabled, alarm.hour, alarm.minute, days)
+        })
+        .collect()
+}
+
+/// Stored credentials, or the ones from cfg.toml on a board that was never set up
+fn load_credentials(storage: &Storage) -> Option<Credentials> {
+    storage
+        .load(CREDENTIALS_KEY)
+        .or_else(|| Credentials::from_config(&APP_CONFIG))
+}
+
+fn save_settings(storage: &mut Storage, settings: &Settings) {
+    if let Err(e) = storage.save(SETTINGS_KEY, settings) {
+        error!("Cannot save settings: {}", e);
+    }
+}
+
+fn run_menu_action(
+    action: menu::Action,
+    menu: &mut Menu,
+    clock: &mut Clock,
+    alarm_clock: &mut AlarmClock,
+    storage: &mut Storage,
+    settings: &mut Settings,
+) {
+    info!("Running menu action {:?}", action);
+    match action {
+        menu::Action::NetworkStatus => {
+            let wifi = match network::link_state() {
+                network::LinkState::Up => "UP",
+                network::LinkState::Down => "--",
+                _ => "..",
+            };
+            let mqtt = if network::mqtt_connected() {
+                "UP"
+            } else {
+                "--"
+            };
+            let ip = match network::ip() {
+                Some(ip) => ip.to_string(),
+                None => network::link_state().name().to_uppercase(),
+            };
+            menu.show_info(&format!("WIFI:{} MQTT:{}", wifi, mqtt), &ip);
+        }
+        menu::Action::About => {
+            let version = format!("V{} {}", build_info::VERSION, build_info::GIT_HASH);
+            let built = build_info::build_time();
+            menu.show_info(&version, built.split(' ').next().unwrap_or(&built));
+        }
+        menu::Action::SyncTime => {
+            if network::ip().is_some() {
+                ntp::resync();
+                menu.show_info("TIME SYNC", "REQUESTED");
+            } else {
+                menu.show_info("TIME SYNC", "NO NETWORK");
+            }
+        }
+        menu::Action::Timezone(name) => match timezone::Tz::parse(name) {
+            Ok(tz) => {
+                clock.set_tz(tz);
+                settings.timezone = Some(name.to_string());
+                save_settings(storage, settings);
+                reschedule_alarms(clock, alarm_clock);
+                let city = name.rsplit('/').next().unwrap_or(name).replace('_', " ");
+                menu.show_info("TIMEZONE SET", &city.to_uppercase());
+            }
+            Err(e) => {
+                error!("Invalid timezone {}: {}", name, e);
+                menu.show_info("TIMEZONE", "INVALID");
+            }
+        },
+        menu::Action::Backlight(on) => {
+            settings.backlight = on;
+            save_settings(storage, settings);
+            menu.show_info("BACKLIGHT", if on { "ON" } else { "OFF" });
+        }
+        menu::Action::AssignButton { button, action } => {
+            settings.buttons[button] = action;
+            save_settings(storage, settings);
+            menu.show_info(
+                &format!("BUTTON {}", BUTTON_NAMES[button]),
+                ACTIONS[action].name,
+            );
+        }
+        menu::Action::AddAlarm { hour, minute } => {
+            let alarm = Alarm {
+                hour,
+                minute,
+                days: Weekdays::DAILY,
+                label: "ALARM".to_string(),
+                enabled: true,
+            };
+            match alarm_clock.add(alarm) {
+                Ok(()) => {
+                    save_alarms(storage, alarm_clock);
+                    reschedule_alarms(clock, alarm_clock);
+                    menu.show_info("ALARM ADDED", &format!("{:02}:{:02} DAILY", hour, minute));
+                }
+                Err(e) => {
+                    error!("Cannot add alarm: {}", e);
+                    menu.show_info("ALARM", "INVALID");
+                }
+            }
+        }
+        menu::Action::ToggleAlarm(index) => {
+            let enabled = alarm_clock.alarms().get(index).map(|alarm| !alarm.enabled);
+            match enabled.map(|enabled| alarm_clock.set_enabled(index, enabled)) {
+                Some(Ok(())) => {
+                    save_alarms(storage, alarm_clock);
+                    reschedule_alarms(clock, alarm_clock);
+                    menu.show_info("ALARM", if enabled == Some(true) { "ON" } else { "OFF" });
+                }
+                _ => menu.show_info("ALARM", "NOT FOUND"),
+            }
+        }
+    }
+}
+
+fn sync_rtc_from_ntp(clock: &mut Clock, storage: &mut Storage) {
+    if let Err(e) = clock.sync_from_ntp(storage) {
+        error!("Cannot sync RTC from NTP: {}", e);
+    }
 }
 
 fn pad_single_digit(num: u32) -> String {
@@ -468,12 +1071,27 @@ fn month_to_abbreviation(month: u32) -> &'static str {
     }
 }
 
+fn weekday_to_abbreviation(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "MON",
+        Weekday::Tue => "TUE",
+        Weekday::Wed => "WED",
+        Weekday::Thu => "THU",
+        Weekday::Fri => "FRI",
+        Weekday::Sat => "SAT",
+        Weekday::Sun => "SUN",
+    }
+}
+
 fn display_clock(
-    lcd: &mut HD44780<I2CBus<I2cProxy<NullMutex<I2cDriver>>>>,
+    display: &mut Display,
     date_time: NaiveDateTime,
     temp: u32,
     humid: u32,
-) -> anyhow::Result<()> {
+    synced: bool,
+    rtc: bool,
+    link: network::LinkState,
+) {
     let hour = pad_single_digit(date_time.hour());
     let minute = pad_single_digit(date_time.minute());
     let day = pad_single_digit(date_time.day());
@@ -482,62 +1100,74 @@ fn display_clock(
     let temp = pad_single_digit(temp);
     let humid = pad_single_digit(humid);
 
-    let first_line = format!("{}:{}  {} {} {}", hour, minute, day, month, year);
-    let second_line = format!("  T {}C  H {}%", temp, humid);
+    // Flag the clock until NTP has confirmed the RTC time at least once, and
+    // while it runs off the system clock because the RTC does not answer
+    let sync_marker = match (rtc, synced) {
+        (false, _) => " R",
+        (true, true) => "  ",
+        (true, false) => " ?",
+    };
+    // And while Wi-Fi is not up
+    let link_marker = match link {
+        network::LinkState::Up => ' ',
+        network::LinkState::Connecting | network::LinkState::Associated => '~',
+        network::LinkState::Down => '!',
+    };
 
-    lcd.set_cursor_pos(0, &mut FreeRtos).unwrap();
-    lcd.write_str(&first_line, &mut FreeRtos).unwrap();
-    lcd.set_cursor_pos(40, &mut FreeRtos).unwrap();
-    lcd.write_str(&second_line, &mut FreeRtos).unwrap();
+    let first_line = format!("{}:{}  {} {} {}", hour, minute, day, month, year);
+    let second_line = format!("{} T {}C  H {}%{}", link_marker, temp, humid, sync_marker);
 
-    Ok(())
+    display.show(&first_line, &second_line);
 }
 
-fn display_aqi(
-    lcd: &mut HD44780<I2CBus<I2cProxy<NullMutex<I2cDriver>>>>,
-    pm2_5: u32,
-    pm10: u32,
-) -> anyhow::Result<()> {
+fn display_aqi(display: &mut Display, pm2_5: u32, pm10: u32) {
     let first_line = format!("PM2.5: {}", pm2_5);
     let second_line = format!("PM10: {}", pm10);
 
-    lcd.set_cursor_pos(0, &mut FreeRtos).unwrap();
-    lcd.write_str(&first_line, &mut FreeRtos).unwrap();
-    lcd.set_cursor_pos(40, &mut FreeRtos).unwrap();
-    lcd.write_str(&second_line, &mut FreeRtos).unwrap();
-
-    Ok(())
+    display.show(&first_line, &second_line);
 }
 
-fn display_message(
-    lcd: &mut HD44780<I2CBus<I2cProxy<NullMutex<I2cDriver>>>>,
-    line_1: &str,
-    line_2: &str,
-) -> anyhow::Result<()> {
-    lcd.clear(&mut FreeRtos).unwrap();
-    lcd.set_cursor_pos(0, &mut FreeRtos).unwrap();
-    lcd.write_str(line_1, &mut FreeRtos).unwrap();
-    lcd.set_cursor_pos(40, &mut FreeRtos).unwrap();
-    lcd.write_str(line_2, &mut FreeRtos).unwrap();
-
-    Ok(())
+fn display_schedules(
+    display: &mut Display,
+    count: usize,
+    next: Option<(&Schedule, NaiveDateTime)>,
+) {
+    let first_line = format!("SCHEDULES: {}", count);
+    let second_line = match next {
+        Some((schedule, run)) => format!(
+            "{} {}:{} {}",
+            weekday_to_abbreviation(run.weekday()),
+            pad_single_digit(run.hour()),
+            pad_single_digit(run.minute()),
+            schedule.payload
+        ),
+        None => "NONE".to_string(),
+    };
+    let second_line: String = second_line.chars().take(16).collect();
+
+    display.show(&first_line, &second_line);
 }
 
-fn handle_alarm_every_minute(
-    rtc: &mut Ds323x<I2cInterface<I2cProxy<NullMutex<I2cDriver>>>, DS3231>,
+fn display_network(
+    display: &mut Display,
+    hostname: &str,
+    ip: Option<Ipv4Addr>,
+    link: network::LinkState,
 ) {
-    let opm = Alarm2Matching::OncePerMinute;
-
-    rtc.clear_alarm2_matched_flag().unwrap();
-    rtc.set_alarm2_day(
-        DayAlarm2 {
-            day: 1,
-            hour: Hours::H24(0),
-            minute: 0,
-        },
-        opm,
-    )
-    .unwrap();
+    let first_line: String = hostname.chars().take(16).collect();
+    let second_line = match ip {
+        Some(ip) => ip.to_string(),
+        None => link.name().to_uppercase(),
+    };
+
+    display.show(&first_line, &second_line);
+}
+
+fn display_alarm(display: &mut Display, label: &str, visible: bool) {
+    let banner = if visible { "*** ALARM ***" } else { "" };
+    let label: String = label.chars().take(16).collect();
+
+    display.message(banner, &label);
 }
 
 fn convert_event_data(raw: &[u8]) -> EnvironmentalInfo {
@@ -1,10 +1,15 @@
+use crate::credentials::{MqttTls, Trust};
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::mqtt::client::{
-    EspMqttClient, EspMqttConnection, MessageId, MqttClientConfiguration, QoS,
+    EspMqttClient, EspMqttConnection, LwtConfiguration, MessageId, MqttClientConfiguration, QoS,
+};
+use esp_idf_svc::sys::{
+    esp, esp_crt_bundle_attach, esp_tls_free_global_ca_store, esp_tls_set_global_ca_store, EspError,
 };
-use esp_idf_svc::sys::EspError;
 use esp_idf_svc::tls::X509;
 use log::{error, info};
+use std::sync::Mutex;
+use std::time::Duration;
 
 static CA: &[u8] = b"-----BEGIN CERTIFICATE-----
 MIIFazCCA1OgAwIBAgIRAIIQz7DSQONZRGPgu2OCiwAwDQYJKoZIhvcNAQELBQAw
@@ -38,41 +43,122 @@ mRGunUHBcnWEvgJBQl9nJEiU0Zsnvgc/ubhPgXRR4Xq37Z0j4r7g1SgEEzwxA57d
 emyPxgcYxn/eR44/KJ4EBs+lVDR3veyJm+kXQ99b21/+jh5Xos1AnX5iItreGCc=
 -----END CERTIFICATE-----\0";
 
+/// Payloads of the availability topic, `offline` is sent by the broker as the
+/// Last Will when the board drops off
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Who the board is to the broker and how the broker keeps track of it
+pub struct Session<'a> {
+    pub client_id: &'a str,
+    pub availability_topic: &'a str,
+    pub keep_alive_secs: u32,
+    /// Drop subscriptions and queued messages when the board reconnects
+    pub clean_session: bool,
+}
+
 pub fn init(
     url: &str,
-    client_id: &str,
+    session: &Session,
     username: &str,
     password: &str,
-) -> Result<(EspMqttClient<'static>, EspMqttConnection), EspError> {
-    let x509 = X509::pem_until_nul(CA);
+    tls: &MqttTls,
+) -> anyhow::Result<(EspMqttClient<'static>, EspMqttConnection)> {
+    tls.validate()?;
 
-    let mqtt_config = MqttClientConfiguration {
-        client_id: Some(client_id),
+    let mut mqtt_config = MqttClientConfiguration {
+        client_id: Some(session.client_id),
+        keep_alive_interval: Some(Duration::from_secs(session.keep_alive_secs as u64)),
+        disable_clean_session: !session.clean_session,
+        lwt: Some(LwtConfiguration {
+            topic: session.availability_topic,
+            payload: OFFLINE.as_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
         username: Some(username),
         password: Some(password),
-        use_global_ca_store: false,
         skip_cert_common_name_check: false,
-        server_certificate: Some(x509),
         ..Default::default()
     };
+    match tls.trust {
+        Trust::Isrg => mqtt_config.server_certificate = Some(X509::pem_until_nul(CA)),
+        Trust::Bundle => mqtt_config.crt_bundle_attach = Some(esp_crt_bundle_attach),
+        Trust::Custom => mqtt_config.server_certificate = tls.ca_cert.as_deref().map(pem),
+        Trust::Global => {
+            if let Some(ca) = &tls.ca_cert {
+                set_global_ca_store(ca)?;
+            }
+            mqtt_config.use_global_ca_store = true;
+        }
+        Trust::None => {}
+    }
+    if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+        info!("Using a client certificate");
+        mqtt_config.client_certificate = Some(pem(cert));
+        mqtt_config.private_key = Some(pem(key));
+    }
 
     let (mqtt_client, mqtt_conn) = EspMqttClient::new(url, &mqtt_config)?;
 
     Ok((mqtt_client, mqtt_conn))
 }
 
-pub fn subscribes(client: &mut EspMqttClient, topic: &str) {
-    info!("About to start the MQTT client");
+/// The client keeps pointers to the certificates, so they live as long as the
+/// firmware runs. A restarted client reuses the earlier copies.
+fn pem(pem: &str) -> X509<'static> {
+    static PEMS: Mutex<Vec<&'static [u8]>> = Mutex::new(Vec::new());
+
+    let mut cached = PEMS.lock().unwrap();
+    // mbedtls wants PEM with its terminating NUL counted in the length
+    let mut bytes = pem.as_bytes().to_vec();
+    bytes.push(0);
+    let pem = match cached.iter().find(|pem| **pem == bytes.as_slice()) {
+        Some(pem) => *pem,
+        None => {
+            let pem: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+            cached.push(pem);
+            pem
+        }
+    };
+    X509::pem_until_nul(pem)
+}
+
+/// ESP-TLS parses the PEM into the store, so it does not need to outlive the
+/// call. Parsing adds to the store, it is emptied first so a restarted client
+/// does not pile up copies.
+fn set_global_ca_store(pem: &str) -> Result<(), EspError> {
+    let mut bytes = pem.as_bytes().to_vec();
+    bytes.push(0);
+    unsafe {
+        esp_tls_free_global_ca_store();
+        esp!(esp_tls_set_global_ca_store(
+            bytes.as_ptr(),
+            bytes.len() as _
+        ))
+    }
+}
 
-    loop {
-        if let Err(e) = client.subscribe(topic, QoS::AtMostOnce) {
-            error!("Failed to subscribe to topic {topic}: {e}, retrying...");
+const SUBSCRIBE_ATTEMPTS: u32 = 3;
 
-            // Re-try in 0.5s
-            FreeRtos::delay_ms(2000);
-        } else {
-            info!("Topic {topic} subscribed");
-            break;
+/// Called on every connect, a subscription that keeps failing is tried again
+/// on the next one. The client is only locked for each attempt, so publishing
+/// goes on while it waits.
+pub fn subscribes(client: &Mutex<Option<EspMqttClient<'static>>>, topic: &str) {
+    for attempt in 1..=SUBSCRIBE_ATTEMPTS {
+        let subscribed = match client.lock().unwrap().as_mut() {
+            Some(client) => client.subscribe(topic, QoS::AtMostOnce),
+            None => return,
+        };
+        match subscribed {
+            Ok(_) => {
+                info!("Topic {topic} subscribed");
+                return;
+            }
+            Err(e) => {
+                error!("Failed to subscribe to topic {topic}: {e}, attempt {attempt}");
+                FreeRtos::delay_ms(500);
+            }
         }
     }
 }
@@ -84,3 +170,16 @@ pub fn send_payload(
 ) -> Result<MessageId, EspError> {
     client.enqueue(topic, QoS::AtMostOnce, false, payload.as_bytes())
 }
+
+/// Retained so anyone subscribing later still sees the board is there
+pub fn send_online(client: &mut EspMqttClient, topic: &str) -> Result<MessageId, EspError> {
+    client.enqueue(topic, QoS::AtLeastOnce, true, ONLINE.as_bytes())
+}
+
+pub fn send_retained(
+    client: &mut EspMqttClient,
+    topic: &str,
+    payload: &str,
+) -> Result<MessageId, EspError> {
+    client.enqueue(topic, QoS::AtMostOnce, true, payload.as_bytes())
+}
@@ -0,0 +1,471 @@
+use crate::backoff::Backoff;
+use crate::credentials::{Credentials, Trust};
+use crate::error::{self, Error};
+use crate::ipconfig::StaticIp;
+use crate::watchdog::Watchdog;
+use crate::{device, discovery, mqtt, ntp, wifi, AppConfig};
+use anyhow::Result;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::task::notification::Notifier;
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload};
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{EspWifi, WifiEvent};
+use log::{error, info, warn};
+use std::net::Ipv4Addr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NETWORK_THREAD_SIZE: usize = 8192;
+const MQTT_THREAD_SIZE: usize = 6000;
+// Fallback check in case a disconnect event goes missing
+const CHECK_INTERVAL_MS: u64 = 30000;
+
+static LINK: AtomicU8 = AtomicU8::new(LinkState::Down as u8);
+// Station address while Wi-Fi is up, 0 otherwise
+static IP: AtomicU32 = AtomicU32::new(0);
+static SSID: Mutex<String> = Mutex::new(String::new());
+static HOSTNAME: Mutex<String> = Mutex::new(String::new());
+static JOINS: AtomicU32 = AtomicU32::new(0);
+static MQTT_UP: AtomicBool = AtomicBool::new(false);
+static MQTT_CONNECTS: AtomicU32 = AtomicU32::new(0);
+
+/// Wi-Fi link as last seen by the supervisor and the driver events
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum LinkState {
+    Down,
+    Connecting,
+    /// Associated with the access point, waiting for an address
+    Associated,
+    Up,
+}
+
+impl LinkState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkState::Down => "down",
+            LinkState::Connecting => "connecting",
+            LinkState::Associated => "associated",
+            LinkState::Up => "up",
+        }
+    }
+}
+
+/// What wakes up the supervisor
+enum Event {
+    WifiDown,
+    MqttConnected,
+    /// The broker went away, the client is dropped and started again later
+    MqttDisconnected,
+    /// The MQTT event loop ended, the client has to be created again
+    MqttClosed,
+}
+
+/// MQTT client shared with the main loop, `None` until one is started
+pub type MqttClient = Arc<Mutex<Option<EspMqttClient<'static>>>>;
+
+/// Bring up Wi-Fi, NTP and MQTT on a background thread so the clock and the
+/// buttons work straight away, even when the network is down. When the driver
+/// or the thread cannot be started the board runs offline.
+pub fn start<F>(
+    app_config: AppConfig,
+    credentials: Option<Credentials>,
+    modem: Modem,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    notifier: Arc<Notifier>,
+    on_message: F,
+) -> MqttClient
+where
+    F: Fn(Option<&str>, &[u8]) + Send + Sync + 'static,
+{
+    let client: MqttClient = Arc::new(Mutex::new(None));
+    let shared_client = Arc::clone(&client);
+    let hostname = device::hostname(app_config.hostname);
+    *HOSTNAME.lock().unwrap() = hostname.clone();
+
+    let Some(credentials) = credentials else {
+        warn!("No WiFi configured, running offline");
+        return client;
+    };
+    let static_ip = StaticIp::parse(
+        app_config.static_ip,
+        app_config.gateway,
+        app_config.dns,
+        app_config.secondary_dns,
+    )
+    .unwrap_or_else(|e| {
+        error!("{}, using DHCP", e);
+        None
+    });
+    let wifi = match wifi::wifi(modem, sysloop.clone(), nvs, &hostname, static_ip) {
+        Ok(wifi) => wifi,
+        Err(e) => {
+            error::record(Error::Wifi(format!("Cannot start, running offline: {}", e)));
+            return client;
+        }
+    };
+
+    let spawned = thread::Builder::new()
+        .stack_size(NETWORK_THREAD_SIZE)
+        .spawn(move || {
+            if let Err(e) = run(
+                app_config,
+                credentials,
+                wifi,
+                sysloop,
+                notifier,
+                shared_client,
+                on_message,
+            ) {
+                error::record(Error::Wifi(format!("Network stopped: {}", e)));
+            }
+        });
+    if let Err(e) = spawned {
+        error::record(Error::Wifi(format!(
+            "Cannot start the network thread, running offline: {}",
+            e
+        )));
+    }
+
+    client
+}
+
+fn run<F>(
+    app_config: AppConfig,
+    credentials: Credentials,
+    mut wifi: Box<EspWifi<'static>>,
+    sysloop: EspSystemEventLoop,
+    notifier: Arc<Notifier>,
+    shared_client: MqttClient,
+    on_message: F,
+) -> Result<()>
+where
+    F: Fn(Option<&str>, &[u8]) + Send + Sync + 'static,
+{
+    // Disconnects wake up the supervisor below, every change wakes up the main loop
+    let (events_tx, events_rx) = mpsc::channel();
+    let wifi_events_tx = events_tx.clone();
+    let wifi_notifier = Arc::clone(&notifier);
+    let _wifi_events = sysloop.subscribe::<WifiEvent, _>(move |event| {
+        match event {
+            WifiEvent::StaConnected => set_link(LinkState::Associated),
+            WifiEvent::StaDisconnected => {
+                IP.store(0, Ordering::SeqCst);
+                set_link(LinkState::Down);
+                let _ = wifi_events_tx.send(Event::WifiDown);
+            }
+            _ => return,
+        }
+        unsafe { wifi_notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
+    })?;
+    let ip_notifier = Arc::clone(&notifier);
+    let _ip_events = sysloop.subscribe::<IpEvent, _>(move |event| {
+        match event {
+            IpEvent::DhcpIpAssigned(assignment) => {
+                IP.store(u32::from(assignment.ip()), Ordering::SeqCst);
+                set_link(LinkState::Up);
+            }
+            IpEvent::DhcpIpDeassigned(_) => {
+                IP.store(0, Ordering::SeqCst);
+                set_link(LinkState::Associated);
+            }
+            _ => return,
+        }
+        unsafe { ip_notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
+    })?;
+
+    // Blocking MQTT and Wi-Fi calls happen here, a hang restarts the board
+    let watchdog = Watchdog::watch_current_task();
+    let mut backoff = Backoff::default();
+    let mut sntp = None;
+    let mut mdns = None;
+    let on_message = Arc::new(on_message);
+    let mut mqtt_wanted = true;
+    let mut mqtt_running = false;
+    let mut mqtt_backoff = Backoff::default();
+    // The supervisor keeps handling Wi-Fi and IP events while MQTT backs off
+    let mut mqtt_restart_at: Option<Instant> = None;
+    let availability_topic = app_config
+        .mqtt_availability_topic
+        .replace("{device_id}", &device::id());
+
+    loop {
+        watchdog.feed();
+        // The link state rather than the events decides, so a stale disconnect is harmless
+        if link_state() != LinkState::Up {
+            set_link(LinkState::Connecting);
+            match wifi::connect(&mut wifi, sysloop.clone(), &credentials.networks, &watchdog) {
+                Ok(ssid) => {
+                    info!("Joined {}", ssid);
+                    *SSID.lock().unwrap() = ssid;
+                    JOINS.fetch_add(1, Ordering::SeqCst);
+                    backoff.reset();
+                    update_ip(&wifi);
+                }
+                Err(e) => {
+                    set_link(LinkState::Down);
+                    let delay = backoff.next_delay_ms();
+                    error::record(Error::Wifi(format!(
+                        "Cannot connect: {}, retrying in {}s",
+                        e,
+                        delay / 1000
+                    )));
+                    watchdog.delay_ms(delay);
+                    continue;
+                }
+            }
+        }
+
+        // NTP, mDNS and MQTT ride out later outages by themselves
+        if sntp.is_none() {
+            match ntp::start(app_config.ntp_sync_interval_hours, Arc::clone(&notifier)) {
+                Ok(started) => sntp = Some(started),
+                Err(e) => error!("Cannot start NTP: {}", e),
+            }
+        }
+        if mdns.is_none() && (app_config.mdns || app_config.mqtt_discovery) {
+            match discovery::start(&hostname(), app_config.mdns) {
+                Ok(started) => mdns = Some(started),
+                Err(e) => error!("Cannot start mDNS: {}", e),
+            }
+        }
+        let mqtt_due = mqtt_restart_at.map_or(true, |at| Instant::now() >= at);
+        if mqtt_wanted && !mqtt_running && mqtt_due {
+            mqtt_restart_at = None;
+            match mqtt_url(&app_config, &credentials, mdns.as_ref()) {
+                Some(url) => match start_mqtt(
+                    &credentials,
+                    &url,
+                    &availability_topic,
+                    &app_config,
+                    &shared_client,
+                    Arc::clone(&on_message),
+                    events_tx.clone(),
+                ) {
+                    Ok(()) => mqtt_running = true,
+                    Err(e) => {
+                        let delay = mqtt_backoff.next_delay_ms();
+                        error::record(Error::Mqtt(format!(
+                            "Cannot start: {}, retrying in {}s",
+                            e,
+                            delay / 1000
+                        )));
+                        mqtt_restart_at =
+                            Some(Instant::now() + Duration::from_millis(delay.into()));
+                    }
+                },
+                None if app_config.mqtt_discovery => {
+                    warn!("No MQTT broker found, looking again later")
+                }
+                None => {
+                    warn!("No MQTT broker configured");
+                    mqtt_wanted = false;
+                }
+            }
+        }
+
+        let mut timeout = Duration::from_millis(CHECK_INTERVAL_MS);
+        if let Some(at) = mqtt_restart_at {
+            timeout = timeout.min(at.saturating_duration_since(Instant::now()));
+        }
+        match events_rx.recv_timeout(timeout) {
+            Ok(Event::WifiDown) => info!("wifi is down. reconnecting"),
+            Ok(Event::MqttConnected) => {
+                info!("MQTT connected");
+                mqtt_backoff.reset();
+                on_mqtt_connected(&app_config, &availability_topic, &shared_client);
+            }
+            Ok(Event::MqttDisconnected) => {
+                // Dropping the client ends its event loop, which then sends MqttClosed
+                *shared_client.lock().unwrap() = None;
+                if mqtt_restart_at.is_none() {
+                    let delay = mqtt_backoff.next_delay_ms();
+                    error::record(Error::Mqtt(format!(
+                        "Disconnected, restarting in {}s",
+                        delay / 1000
+                    )));
+                    mqtt_restart_at = Some(Instant::now() + Duration::from_millis(delay.into()));
+                }
+            }
+            Ok(Event::MqttClosed) => {
+                *shared_client.lock().unwrap() = None;
+                mqtt_running = false;
+                if mqtt_restart_at.is_none() {
+                    let delay = mqtt_backoff.next_delay_ms();
+                    error::record(Error::Mqtt(format!(
+                        "Connection closed, restarting in {}s",
+                        delay / 1000
+                    )));
+                    mqtt_restart_at = Some(Instant::now() + Duration::from_millis(delay.into()));
+                }
+            }
+            Err(_) => update_ip(&wifi),
+        }
+    }
+}
+
+/// The configured broker, or one found on the local network when none is
+fn mqtt_url(
+    app_config: &AppConfig,
+    credentials: &Credentials,
+    mdns: Option<&EspMdns>,
+) -> Option<String> {
+    if !credentials.mqtt_url.is_empty() {
+        return Some(credentials.mqtt_url.clone());
+    }
+    if !app_config.mqtt_discovery {
+        return None;
+    }
+    discovery::find_broker(mdns?)
+}
+
+fn start_mqtt<F>(
+    credentials: &Credentials,
+    url: &str,
+    availability_topic: &str,
+    app_config: &AppConfig,
+    shared_client: &MqttClient,
+    on_message: Arc<F>,
+    events: mpsc::Sender<Event>,
+) -> Result<()>
+where
+    F: Fn(Option<&str>, &[u8]) + Send + Sync + 'static,
+{
+    if credentials.mqtt_tls.trust == Trust::None && url.starts_with("mqtts") {
+        warn!("TLS broker {} with no trust configured", url);
+    }
+    // Boards on the same broker must not share a client id or they kick each other off
+    let client_id = format!("button-board-{}", device::id());
+    let session = mqtt::Session {
+        client_id: &client_id,
+        availability_topic,
+        keep_alive_secs: app_config.mqtt_keep_alive_secs,
+        clean_session: app_config.mqtt_clean_session,
+    };
+    let (mqtt_client, mut conn) = mqtt::init(
+        url,
+        &session,
+        &credentials.mqtt_user,
+        &credentials.mqtt_password,
+        &credentials.mqtt_tls,
+    )?;
+    info!("MQTT client id {}", client_id);
+    *shared_client.lock().unwrap() = Some(mqtt_client);
+
+    // A lost connection is restarted by the supervisor with a backoff.
+    // Subscribing blocks until the event is taken off this connection, so it
+    // is left to the supervisor.
+    thread::Builder::new()
+        .stack_size(MQTT_THREAD_SIZE)
+        .spawn(move || {
+            info!("MQTT Listening for messages");
+            while let Ok(event) = conn.next() {
+                match event.payload() {
+                    EventPayload::Connected(_) => {
+                        MQTT_UP.store(true, Ordering::SeqCst);
+                        MQTT_CONNECTS.fetch_add(1, Ordering::SeqCst);
+                        let _ = events.send(Event::MqttConnected);
+                    }
+                    EventPayload::Disconnected => {
+                        MQTT_UP.store(false, Ordering::SeqCst);
+                        warn!("MQTT disconnected");
+                        let _ = events.send(Event::MqttDisconnected);
+                    }
+                    EventPayload::Received { topic, data, .. } => on_message(topic, data),
+                    _ => {}
+                }
+            }
+            info!("Connection closed");
+            MQTT_UP.store(false, Ordering::SeqCst);
+            let _ = events.send(Event::MqttClosed);
+        })?;
+
+    Ok(())
+}
+
+/// Subscriptions do not outlive a clean session, so they are made again on
+/// every connect
+fn on_mqtt_connected(app_config: &AppConfig, availability_topic: &str, shared_client: &MqttClient) {
+    mqtt::subscribes(shared_client, app_config.mqtt_room_topic);
+    if !app_config.mqtt_control_topic.is_empty() {
+        mqtt::subscribes(shared_client, app_config.mqtt_control_topic);
+    }
+    let mut client = shared_client.lock().unwrap();
+    let Some(client) = client.as_mut() else {
+        return;
+    };
+    if let Err(e) = mqtt::send_online(client, availability_topic) {
+        error!("Cannot publish availability: {}", e);
+    }
+}
+
+fn set_link(state: LinkState) {
+    LINK.store(state as u8, Ordering::SeqCst);
+}
+
+pub fn link_state() -> LinkState {
+    match LINK.load(Ordering::SeqCst) {
+        1 => LinkState::Connecting,
+        2 => LinkState::Associated,
+        3 => LinkState::Up,
+        _ => LinkState::Down,
+    }
+}
+
+/// Name of the network last joined
+pub fn ssid() -> String {
+    SSID.lock().unwrap().clone()
+}
+
+/// Name the board announces to DHCP and on the network
+pub fn hostname() -> String {
+    HOSTNAME.lock().unwrap().clone()
+}
+
+/// Networks joined since boot after the first one
+pub fn reconnects() -> u32 {
+    JOINS.load(Ordering::SeqCst).saturating_sub(1)
+}
+
+/// Catch up with the driver in case an event was missed
+fn update_ip(wifi: &EspWifi<'static>) {
+    let ip = if wifi.is_connected().unwrap_or(false) {
+        wifi.sta_netif()
+            .get_ip_info()
+            .map(|info| u32::from(info.ip))
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    IP.store(ip, Ordering::SeqCst);
+    if ip != 0 {
+        set_link(LinkState::Up);
+    } else if link_state() == LinkState::Up {
+        set_link(LinkState::Down);
+    }
+}
+
+/// Station address, `None` while Wi-Fi is down
+pub fn ip() -> Option<Ipv4Addr> {
+    match IP.load(Ordering::SeqCst) {
+        0 => None,
+        ip => Some(Ipv4Addr::from(ip)),
+    }
+}
+
+pub fn mqtt_connected() -> bool {
+    MQTT_UP.load(Ordering::SeqCst)
+}
+
+/// Broker connections since boot after the first one
+pub fn mqtt_reconnects() -> u32 {
+    MQTT_CONNECTS.load(Ordering::SeqCst).saturating_sub(1)
+}
@@ -0,0 +1,46 @@
+use esp_idf_svc::hal::task::notification::Notifier;
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+use esp_idf_svc::sys::{esp_sntp_restart, esp_sntp_set_sync_interval, EspError};
+use log::info;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+static SYNCED: AtomicBool = AtomicBool::new(false);
+static SYNC_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Start SNTP, re-syncing every `interval_hours`. Each successful sync marks the
+/// RTC as needing a check against the fresh system time and wakes up the main loop.
+pub fn start(interval_hours: u32, notifier: Arc<Notifier>) -> Result<EspSntp<'static>, EspError> {
+    let sntp = EspSntp::new_with_callback(&SntpConf::default(), move |synced| {
+        info!("NTP synced: {:?}", synced);
+        SYNCED.store(true, Ordering::SeqCst);
+        SYNC_PENDING.store(true, Ordering::SeqCst);
+        unsafe { notifier.notify_and_yield(NonZeroU32::new(1).unwrap()) };
+    })?;
+
+    unsafe {
+        esp_sntp_set_sync_interval(interval_hours.clamp(1, 24 * 7) * 3600 * 1000);
+        // The new interval only applies after a restart
+        esp_sntp_restart();
+    }
+
+    Ok(sntp)
+}
+
+/// Ask for a sync right away instead of waiting for the next interval
+pub fn resync() {
+    info!("Requesting NTP resync");
+    unsafe {
+        esp_sntp_restart();
+    }
+}
+
+/// Whether NTP has synced at least once since boot
+pub fn is_synced() -> bool {
+    SYNCED.load(Ordering::SeqCst)
+}
+
+pub fn take_sync_pending() -> bool {
+    SYNC_PENDING.swap(false, Ordering::SeqCst)
+}
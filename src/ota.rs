@@ -0,0 +1,183 @@
+use crate::network;
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::reset;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate, SlotState};
+use esp_idf_svc::sys::esp_crt_bundle_attach;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+
+const OTA_THREAD_SIZE: usize = 8192;
+const CONFIRM_THREAD_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 1024;
+// Progress while no update runs
+const IDLE: u8 = u8::MAX;
+
+static PROGRESS: AtomicU8 = AtomicU8::new(IDLE);
+
+/// Percentage written while an update runs
+pub fn progress() -> Option<u8> {
+    match PROGRESS.load(Ordering::SeqCst) {
+        IDLE => None,
+        percent => Some(percent),
+    }
+}
+
+/// Download the image at `url` into the spare slot on a background thread and
+/// boot it. The image is only switched to when its SHA-256 matches `sha256`.
+/// Only `https://` is accepted, the server is checked against the certificate bundle.
+pub fn start(url: String, sha256: &str) -> Result<()> {
+    let expected = parse_sha256(sha256)?;
+    if !url.starts_with("https://") {
+        bail!("Update URL has to use https: {}", url)
+    }
+    if PROGRESS
+        .compare_exchange(IDLE, 0, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        bail!("An update is already running")
+    }
+
+    thread::Builder::new()
+        .stack_size(OTA_THREAD_SIZE)
+        .spawn(move || {
+            info!("Updating from {}", url);
+            match install(&url, &expected) {
+                Ok(()) => {
+                    info!("Update installed, restarting");
+                    FreeRtos::delay_ms(1000);
+                    reset::restart();
+                }
+                Err(e) => error!("Update failed: {}", e),
+            }
+            PROGRESS.store(IDLE, Ordering::SeqCst);
+        })
+        .inspect_err(|_| PROGRESS.store(IDLE, Ordering::SeqCst))?;
+
+    Ok(())
+}
+
+fn install(url: &str, expected: &[u8; 32]) -> Result<()> {
+    let mut conn = EspHttpConnection::new(&HttpConfiguration {
+        buffer_size: Some(CHUNK_SIZE),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    conn.initiate_request(Method::Get, url, &[])?;
+    conn.initiate_response()?;
+    if conn.status() != 200 {
+        bail!("Server answered {}", conn.status())
+    }
+    let total: Option<usize> = conn
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok());
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    match write_image(&mut conn, &mut update, total) {
+        Ok(digest) if digest == *expected => {
+            // Checks the image and makes its slot the boot one
+            update.complete()?;
+            Ok(())
+        }
+        Ok(_) => {
+            update.abort()?;
+            bail!("SHA-256 mismatch")
+        }
+        Err(e) => {
+            update.abort()?;
+            Err(e)
+        }
+    }
+}
+
+/// Copy the download into the update slot, returning its SHA-256
+fn write_image(
+    conn: &mut EspHttpConnection,
+    update: &mut EspOtaUpdate,
+    total: Option<usize>,
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let len = conn.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        update.write(&buf[..len])?;
+        written += len;
+        if let Some(total) = total.filter(|total| *total > 0) {
+            PROGRESS.store((written * 100 / total).min(99) as u8, Ordering::SeqCst);
+        }
+    }
+    if total.is_some_and(|total| total != written) {
+        bail!("Download ended after {} bytes", written)
+    }
+    info!("Downloaded {} bytes", written);
+    PROGRESS.store(100, Ordering::SeqCst);
+
+    Ok(hasher.finalize().into())
+}
+
+fn parse_sha256(hex: &str) -> Result<[u8; 32]> {
+    let hex = hex.trim();
+    let mut digest = [0; 32];
+    if hex.len() != 64 {
+        bail!("SHA-256 must be 64 hex digits")
+    }
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = hex
+            .get(i * 2..i * 2 + 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| anyhow!("Invalid SHA-256 {}", hex))?;
+    }
+    Ok(digest)
+}
+
+/// A freshly installed image has to prove itself by reaching the broker
+/// within `window_secs`. Otherwise the bootloader goes back to the previous
+/// image on the next start, which this triggers right away. With a window of
+/// 0 the image is confirmed straight away.
+pub fn confirm_when_healthy(window_secs: u32) -> Result<()> {
+    // Only held for a moment, an update may be started meanwhile
+    if EspOta::new()?.get_running_slot()?.state != SlotState::Unverified {
+        return Ok(());
+    }
+    if window_secs == 0 {
+        EspOta::new()?.mark_running_slot_valid()?;
+        info!("New image confirmed");
+        return Ok(());
+    }
+    info!("Running a new image, confirming within {}s", window_secs);
+
+    thread::Builder::new()
+        .stack_size(CONFIRM_THREAD_SIZE)
+        .spawn(move || {
+            for _ in 0..window_secs {
+                if network::mqtt_connected() {
+                    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+                        Ok(()) => info!("New image confirmed"),
+                        Err(e) => error!("Cannot confirm the new image: {}", e),
+                    }
+                    return;
+                }
+                FreeRtos::delay_ms(1000);
+            }
+            error!("New image never reached the broker, rolling back");
+            match EspOta::new() {
+                Ok(mut ota) => {
+                    let e = ota.mark_running_slot_invalid_and_reboot();
+                    error!("Cannot roll back: {}", e);
+                }
+                Err(e) => error!("Cannot roll back: {}", e),
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use crate::credentials::Credentials;
+use crate::roaming::{self, KnownNetwork, Security};
+use anyhow::Result;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi,
+};
+use log::{error, info};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+
+/// Name of the open access point the setup portal runs on
+pub const AP_SSID: &str = "button-board";
+const HTTP_STACK_SIZE: usize = 10240;
+const DNS_THREAD_SIZE: usize = 4096;
+const MAX_FORM_LEN: usize = 1024;
+
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><body><h1>Saved</h1>\
+<p>The board restarts and joins the network now.</p></body></html>";
+
+/// Run the setup portal until the form is submitted and `save` keeps it. The
+/// board is its own access point, answers every DNS query with its address so
+/// phones open the portal by themselves, and serves a form for the Wi-Fi and
+/// MQTT settings. When saving fails the browser is told and the portal stays up.
+pub fn run(
+    modem: Modem,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    current: &Credentials,
+    mut save: impl FnMut(&Credentials) -> Result<()>,
+) -> Result<()> {
+    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs))?;
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: AP_SSID.try_into().unwrap(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!("Setup portal on {} at http://{}", AP_SSID, ip);
+
+    thread::Builder::new()
+        .stack_size(DNS_THREAD_SIZE)
+        .spawn(move || {
+            if let Err(e) = serve_dns(ip) {
+                error!("Setup DNS stopped: {}", e);
+            }
+        })?;
+
+    let (tx, rx) = mpsc::channel();
+    let page = form_page(current);
+    let current = current.clone();
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        stack_size: HTTP_STACK_SIZE,
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    server.fn_handler::<anyhow::Error, _>("/save", Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > MAX_FORM_LEN {
+            req.into_status_response(413)?
+                .write_all(b"Request too big")?;
+            return Ok(());
+        }
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+
+        let Some(credentials) = parse_form(&String::from_utf8_lossy(&buf), &current) else {
+            req.into_status_response(400)?
+                .write_all(b"Invalid Wi-Fi name, password or access point")?;
+            return Ok(());
+        };
+        let (saved_tx, saved_rx) = mpsc::channel();
+        tx.send((credentials, saved_tx))?;
+        match saved_rx.recv()? {
+            Ok(()) => req.into_ok_response()?.write_all(SAVED_PAGE.as_bytes())?,
+            Err(e) => req
+                .into_status_response(500)?
+                .write_all(format!("Cannot save: {}", e).as_bytes())?,
+        }
+        Ok(())
+    })?;
+    // Every other page, including the connectivity checks phones make, gets the form
+    server.fn_handler::<anyhow::Error, _>("/*", Method::Get, move |req| {
+        req.into_ok_response()?.write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+
+    loop {
+        let (credentials, saved_tx) = rx.recv()?;
+        let saved = save(&credentials);
+        if let Err(e) = &saved {
+            error!("Cannot save the credentials: {:?}", e);
+        }
+        let done = saved.is_ok();
+        let _ = saved_tx.send(saved.map_err(|e| e.to_string()));
+        if done {
+            info!("Setup done, {} known networks", credentials.networks.len());
+            break;
+        }
+    }
+    // Let the response reach the browser before the server goes away
+    FreeRtos::delay_ms(1000);
+
+    Ok(())
+}
+
+/// The entered network is added to the known ones. Non secret MQTT fields are
+/// filled in with the current values. Stored passwords are never put in the
+/// page, leaving a password blank keeps the stored one. Passwords that are
+/// typed in do cross the open access point unencrypted.
+fn form_page(current: &Credentials) -> String {
+    let known = current
+        .networks
+        .iter()
+        .map(|network| html_escape(&network.ssid))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let known = if known.is_empty() {
+        "none".to_string()
+    } else {
+        known
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Button board setup</title></head><body><h1>Button board setup</h1>\
+<p>Known networks: {}</p>\
+<form method=\"post\" action=\"/save\">\
+<p><label>Wi-Fi name<br><input name=\"wifi_ssid\" required></label></p>\
+<p><label>Wi-Fi password<br><input name=\"wifi_psk\" type=\"password\"></label></p>\
+<p><label>Security<br><select name=\"security\">\
+<option value=\"auto\">Automatic</option><option value=\"wpa2\">WPA2</option>\
+<option value=\"wpa3\">WPA3</option><option value=\"wpa2-wpa3\">WPA2/WPA3</option>\
+<option value=\"open\">Open</option></select></label></p>\
+<p><label><input name=\"hidden\" type=\"checkbox\"> Hidden network</label></p>\
+<p><label>Access point (optional)<br><input name=\"bssid\" placeholder=\"aa:bb:cc:dd:ee:ff\"></label></p>\
+<p><label>Priority<br><input name=\"priority\" type=\"number\" min=\"0\" max=\"255\" value=\"0\"></label></p>\
+<p><label><input name=\"forget\" type=\"checkbox\"> Forget the other networks</label></p>\
+<p><label>MQTT URL<br><input name=\"mqtt_url\" value=\"{}\" placeholder=\"mqtts://broker:8883\"></label></p>\
+<p><label>MQTT user<br><input name=\"mqtt_user\" value=\"{}\"></label></p>\
+<p><label>MQTT password<br><input name=\"mqtt_password\" type=\"password\" placeholder=\"blank keeps the saved one\"></label></p>\
+<p><button type=\"submit\">Save</button></p></form></body></html>",
+        known,
+        html_escape(&current.mqtt_url),
+        html_escape(&current.mqtt_user),
+    )
+}
+
+/// Apply the submitted form to the current credentials, `None` without a
+/// network name or with an access point that is no BSSID
+fn parse_form(body: &str, current: &Credentials) -> Option<Credentials> {
+    let mut credentials = current.clone();
+    let mut network = KnownNetwork::default();
+    let mut forget = false;
+    for pair in body.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = url_decode(value);
+        match key {
+            "wifi_ssid" => network.ssid = value.trim().to_string(),
+            "wifi_psk" => network.psk = value,
+            "priority" => network.priority = value.trim().parse().unwrap_or(0),
+            "security" => network.security = value.parse().unwrap_or_default(),
+            "hidden" => network.hidden = true,
+            // A mistyped access point must not quietly save the network unlocked
+            "bssid" if !value.trim().is_empty() => network.bssid = Some(value.trim().parse().ok()?),
+            "forget" => forget = true,
+            "mqtt_url" => credentials.mqtt_url = value.trim().to_string(),
+            "mqtt_user" => credentials.mqtt_user = value,
+            "mqtt_password" if !value.is_empty() => credentials.mqtt_password = value,
+            _ => {}
+        }
+    }
+    // A known network entered again without a password keeps its password
+    if network.psk.is_empty() && network.security != Security::Open {
+        if let Some(known) = current.networks.iter().find(|n| n.ssid == network.ssid) {
+            network.psk = known.psk.clone();
+        }
+    }
+    if network.validate().is_err() {
+        return None;
+    }
+    if forget {
+        credentials.networks.clear();
+    }
+    roaming::remember(&mut credentials.networks, network);
+    Some(credentials)
+}
+
+/// Decode a form value, where spaces come as '+' and other bytes as %XX
+fn url_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match raw
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn html_escape(raw: &str) -> String {
+    raw.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn serve_dns(ip: Ipv4Addr) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buf = [0; 512];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            if let Err(e) = socket.send_to(&reply, peer) {
+                error!("Cannot answer DNS query: {}", e);
+            }
+        }
+    }
+}
+
+/// Answer the first question of a query with `ip`, whatever name it asks for
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < 12 {
+        return None;
+    }
+    // The question is a list of length prefixed labels, then type and class
+    let mut end = 12;
+    while *query.get(end)? != 0 {
+        end += *query.get(end)? as usize + 1;
+    }
+    end += 5;
+    if query.len() < end {
+        return None;
+    }
+
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // Response, recursion desired and available, one question and one answer
+    reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+    // Name pointing back at the question, type A, class IN, TTL 60s, 4 bytes
+    reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    reply.extend_from_slice(&ip.octets());
+    Some(reply)
+}
@@ -0,0 +1,228 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How the board authenticates with a network. `Auto` picks WPA2 (or better)
+/// when there is a password and an open network otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    #[default]
+    Auto,
+    Open,
+    Wpa2,
+    Wpa3,
+    Wpa2Wpa3,
+    Enterprise,
+}
+
+impl FromStr for Security {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(Security::Auto),
+            "open" | "none" => Ok(Security::Open),
+            "wpa2" => Ok(Security::Wpa2),
+            "wpa3" => Ok(Security::Wpa3),
+            "wpa2-wpa3" => Ok(Security::Wpa2Wpa3),
+            "enterprise" | "wpa2-enterprise" => Ok(Security::Enterprise),
+            _ => bail!("Unknown Wi-Fi security \"{}\"", name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EapMethod {
+    #[default]
+    Peap,
+    Ttls,
+}
+
+impl FromStr for EapMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "peap" => Ok(EapMethod::Peap),
+            "ttls" => Ok(EapMethod::Ttls),
+            _ => bail!("Unknown EAP method \"{}\"", name),
+        }
+    }
+}
+
+/// WPA2-Enterprise login. The outer identity is sent in the clear, the
+/// username and password only inside the TLS tunnel.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Enterprise {
+    #[serde(default)]
+    pub method: EapMethod,
+    #[serde(default)]
+    pub identity: String,
+    pub username: String,
+    pub password: String,
+    /// PEM of the CA the RADIUS server certificate is checked against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>,
+}
+
+/// MAC address of an access point, written as "aa:bb:cc:dd:ee:ff"
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Bssid(pub [u8; 6]);
+
+impl FromStr for Bssid {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let mut bssid = [0; 6];
+        let mut parts = raw.trim().split([':', '-']);
+        for byte in bssid.iter_mut() {
+            *byte = parts
+                .next()
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid BSSID \"{}\"", raw))?;
+        }
+        if parts.next().is_some() {
+            bail!("Invalid BSSID \"{}\"", raw)
+        }
+        Ok(Bssid(bssid))
+    }
+}
+
+impl fmt::Display for Bssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl TryFrom<String> for Bssid {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: String) -> Result<Self> {
+        raw.parse()
+    }
+}
+
+impl From<Bssid> for String {
+    fn from(bssid: Bssid) -> Self {
+        bssid.to_string()
+    }
+}
+
+/// A Wi-Fi network the board may join. Higher priority wins when several are
+/// in range, signal strength decides between equal priorities.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub psk: String,
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub security: Security,
+    /// Required with [`Security::Enterprise`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enterprise: Option<Enterprise>,
+    /// The access point does not broadcast its name, so scans cannot find it
+    #[serde(default)]
+    pub hidden: bool,
+    /// Only join this one access point of the network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bssid: Option<Bssid>,
+}
+
+impl KnownNetwork {
+    pub fn validate(&self) -> Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            bail!("SSID must be 1 to 32 bytes")
+        }
+        if self.psk.len() > 64 {
+            bail!("Password of {} is too long", self.ssid)
+        }
+        let needs_psk = matches!(
+            self.security,
+            Security::Wpa2 | Security::Wpa3 | Security::Wpa2Wpa3
+        );
+        if needs_psk && self.psk.len() < 8 {
+            bail!("Password of {} is too short", self.ssid)
+        }
+        if self.security == Security::Enterprise && self.enterprise.is_none() {
+            bail!("Enterprise login missing for {}", self.ssid)
+        }
+        Ok(())
+    }
+
+    fn matches(&self, ap: &Seen) -> bool {
+        let named = ap.ssid == self.ssid || (self.hidden && ap.ssid.is_empty());
+        match self.bssid {
+            Some(bssid) => named && ap.bssid == bssid.0,
+            // A hidden access point without a BSSID cannot be told apart from others
+            None => ap.ssid == self.ssid,
+        }
+    }
+}
+
+/// An access point found by a scan
+#[derive(Clone, Copy, Debug)]
+pub struct Seen<'a> {
+    pub ssid: &'a str,
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate<'a> {
+    pub network: &'a KnownNetwork,
+    /// Channel the access point was seen on, if it was
+    pub channel: Option<u8>,
+    rssi: Option<i8>,
+}
+
+/// Order to try the known networks in. Networks found by the scan come first,
+/// best priority then strongest signal, along with hidden networks which a scan
+/// cannot find. The rest follow by priority, as a scan can miss an access point.
+pub fn candidates<'a>(known: &'a [KnownNetwork], seen: &[Seen]) -> Vec<Candidate<'a>> {
+    let mut likely = Vec::new();
+    let mut unlikely = Vec::new();
+    for network in known {
+        let best = seen
+            .iter()
+            .filter(|ap| network.matches(ap))
+            .max_by_key(|ap| ap.rssi);
+        let candidate = Candidate {
+            network,
+            channel: best.map(|ap| ap.channel),
+            rssi: best.map(|ap| ap.rssi),
+        };
+        if best.is_some() || network.hidden {
+            likely.push(candidate);
+        } else {
+            unlikely.push(candidate);
+        }
+    }
+
+    likely.sort_by(|a, b| {
+        b.network
+            .priority
+            .cmp(&a.network.priority)
+            .then(b.rssi.cmp(&a.rssi))
+    });
+    unlikely.sort_by(|a, b| b.network.priority.cmp(&a.network.priority));
+    likely.extend(unlikely);
+    likely
+}
+
+/// Add a network, replacing the one with the same name
+pub fn remember(known: &mut Vec<KnownNetwork>, network: KnownNetwork) {
+    known.retain(|n| n.ssid != network.ssid);
+    known.push(network);
+}
@@ -0,0 +1,22 @@
+use crate::actions::{Assignments, DEFAULT_ASSIGNMENTS};
+use serde::{Deserialize, Serialize};
+
+/// Preferences changed from the on-device menu, saved to NVS as a whole
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Overrides the timezone from the config when set
+    pub timezone: Option<String>,
+    pub backlight: bool,
+    pub buttons: Assignments,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            timezone: None,
+            backlight: true,
+            buttons: DEFAULT_ASSIGNMENTS,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const NAMESPACE: &str = "button-board";
+
+/// Settings and state that need to survive a reboot, kept in the default NVS partition
+pub struct Storage {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(Storage { nvs })
+    }
+
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        match self.nvs.get_i64(key) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Cannot read {} from NVS: {}", key, e);
+                None
+            }
+        }
+    }
+
+    pub fn set_i64(&mut self, key: &str, value: i64) -> Result<()> {
+        self.nvs.set_i64(key, value)?;
+        Ok(())
+    }
+
+    /// Read a value stored as JSON with [`Storage::save`]
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let raw = match self.read(key) {
+            Ok(raw) => raw?,
+            Err(e) => {
+                error!("Cannot read {} from NVS: {}", key, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&raw) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                error!("Cannot parse {} from NVS: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Stored as a blob, NVS strings stop at about 4000 bytes and credentials
+    /// with certificates go past that
+    pub fn save<T: Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let raw = serde_json::to_vec(value)?;
+        self.nvs.set_blob(key, &raw)?;
+        Ok(())
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        Ok(self.nvs.get_blob(key, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::actions::BUTTON_NAMES;
+use crate::{build_info, device, error, event, mqtt, network};
+use esp_idf_svc::sys::{
+    esp, esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time,
+    esp_wifi_sta_get_ap_info, wifi_ap_record_t,
+};
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Board health, published retained so the last report stays on the broker
+#[derive(Debug, Serialize)]
+pub struct Telemetry {
+    pub device_id: String,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_time: String,
+    pub features: &'static str,
+    pub uptime_s: u64,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub rssi: Option<i8>,
+    pub channel: Option<u8>,
+    pub ip: Option<String>,
+    pub reset_reason: &'static str,
+    pub wifi_reconnects: u32,
+    pub mqtt_reconnects: u32,
+    /// Presses per button since boot
+    pub presses: BTreeMap<&'static str, u32>,
+    /// Errors per kind since boot
+    pub errors: BTreeMap<&'static str, u32>,
+    pub last_error: Option<String>,
+}
+
+pub fn collect() -> Telemetry {
+    let (rssi, channel) = match ap_info() {
+        Some(ap) => (Some(ap.rssi), Some(ap.primary)),
+        None => (None, None),
+    };
+
+    Telemetry {
+        device_id: device::id(),
+        version: build_info::VERSION,
+        git_hash: build_info::GIT_HASH,
+        build_time: build_info::build_time(),
+        features: build_info::FEATURES,
+        uptime_s: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+        free_heap: unsafe { esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        rssi,
+        channel,
+        ip: network::ip().map(|ip| ip.to_string()),
+        reset_reason: device::reset_reason(),
+        wifi_reconnects: network::reconnects(),
+        mqtt_reconnects: network::mqtt_reconnects(),
+        presses: BUTTON_NAMES.into_iter().zip(event::presses()).collect(),
+        errors: error::counts(),
+        last_error: error::last(),
+    }
+}
+
+pub fn publish(mqtt_client: &network::MqttClient, topic: &str) {
+    if !network::mqtt_connected() {
+        return;
+    }
+    let payload = match serde_json::to_string(&collect()) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Cannot encode telemetry: {}", e);
+            return;
+        }
+    };
+    if let Some(client) = mqtt_client.lock().unwrap().as_mut() {
+        if let Err(e) = mqtt::send_retained(client, topic, &payload) {
+            error!("Cannot publish telemetry: {}", e);
+        }
+    }
+}
+
+/// The access point the station is connected to, `None` while it is not
+fn ap_info() -> Option<wifi_ap_record_t> {
+    let mut ap = wifi_ap_record_t::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap) }).ok()?;
+    Some(ap)
+}
@@ -0,0 +1,61 @@
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::sys::{esp, esp_task_wdt_add, esp_task_wdt_delete, esp_task_wdt_reset};
+use log::error;
+use std::marker::PhantomData;
+
+/// Longest a watched task may go without feeding the watchdog, well inside
+/// CONFIG_ESP_TASK_WDT_TIMEOUT_S from sdkconfig.defaults
+pub const FEED_INTERVAL_MS: u32 = 20000;
+
+/// The calling task on the task watchdog, which panics and restarts the chip
+/// when the task stops feeding it. The watchdog itself is set up by ESP-IDF.
+pub struct Watchdog {
+    subscribed: bool,
+    // Feeding and unsubscribing act on the calling task, so this stays on it
+    _task: PhantomData<*const ()>,
+}
+
+impl Watchdog {
+    /// A task that cannot be watched still runs, just without the watchdog
+    pub fn watch_current_task() -> Self {
+        let subscribed = match esp!(unsafe { esp_task_wdt_add(std::ptr::null_mut()) }) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Cannot watch task: {}", e);
+                false
+            }
+        };
+        Watchdog {
+            subscribed,
+            _task: PhantomData,
+        }
+    }
+
+    pub fn feed(&self) {
+        if self.subscribed {
+            if let Err(e) = esp!(unsafe { esp_task_wdt_reset() }) {
+                error!("Cannot feed watchdog: {}", e);
+            }
+        }
+    }
+
+    /// Sleep, feeding the watchdog along the way
+    pub fn delay_ms(&self, ms: u32) {
+        let mut left = ms;
+        while left > 0 {
+            self.feed();
+            let step = left.min(FEED_INTERVAL_MS);
+            FreeRtos::delay_ms(step);
+            left -= step;
+        }
+        self.feed();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if self.subscribed {
+            let _ = esp!(unsafe { esp_task_wdt_delete(std::ptr::null_mut()) });
+        }
+    }
+}
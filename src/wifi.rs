@@ -1,28 +1,79 @@
+use crate::ipconfig::StaticIp;
+use crate::roaming::{self, EapMethod, Enterprise, KnownNetwork, Security, Seen};
+use crate::watchdog::Watchdog;
 use anyhow::{bail, Result};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::ipv4::{
+    ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
+    Configuration as IpConfiguration, DHCPClientSettings, Mask, Subnet,
+};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{
+    esp, esp_eap_client_clear_ca_cert, esp_eap_client_set_ca_cert, esp_eap_client_set_identity,
+    esp_eap_client_set_password, esp_eap_client_set_ttls_phase2_method,
+    esp_eap_client_set_username, esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAPV2,
+    esp_netif_set_hostname, esp_wifi_sta_enterprise_disable, esp_wifi_sta_enterprise_enable,
+};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::peripheral,
-    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+    wifi::{
+        AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi, PmfConfiguration,
+        WifiDriver,
+    },
 };
-use log::info;
+use log::{error, info, warn};
+use std::ffi::CString;
+use std::sync::Mutex;
 
+/// Create and start the station, with DHCP or `static_ip` for its address
 pub fn wifi(
-    ssid: &str,
-    pass: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
     nvs: EspDefaultNvsPartition,
+    hostname: &str,
+    static_ip: Option<StaticIp>,
 ) -> Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
-        bail!("Missing WiFi name")
-    }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
+    let driver = WifiDriver::new(modem, sysloop.clone(), Some(nvs))?;
+
+    let ip_configuration = match static_ip {
+        Some(settings) => {
+            info!("Using static address {}/{}", settings.ip, settings.prefix);
+            IpClientConfiguration::Fixed(IpClientSettings {
+                ip: settings.ip,
+                subnet: Subnet {
+                    gateway: settings.gateway,
+                    mask: Mask(settings.prefix),
+                },
+                dns: settings.dns,
+                secondary_dns: settings.secondary_dns,
+            })
+        }
+        None => IpClientConfiguration::DHCP(DHCPClientSettings {
+            hostname: hostname.try_into().ok(),
+        }),
+    };
+    let sta_netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: IpConfiguration::Client(ip_configuration),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
+    // DHCP sends the hostname by itself, a fixed address still needs it for the netif
+    let c_hostname = CString::new(hostname)?;
+    unsafe {
+        esp!(esp_netif_set_hostname(
+            sta_netif.handle(),
+            c_hostname.as_ptr()
+        ))?;
     }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs))?;
+    info!("Hostname {}", hostname);
+
+    let mut esp_wifi = EspWifi::wrap_all(
+        driver,
+        sta_netif,
+        #[cfg(esp_idf_esp_wifi_softap_support)]
+        EspNetif::new(NetifStack::Ap)?,
+    )?;
     let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
@@ -31,39 +82,109 @@ pub fn wifi(
 
     wifi.start()?;
 
-    // sysloop.subscribe::<WifiEvent, _>(move |wifi_event| {
-    //     info!("some kind of wifi event {:?}", wifi_event)
-    // })?;
+    Ok(Box::new(esp_wifi))
+}
+
+/// Scan and join the best known network in range, falling back to the next
+/// one when joining fails. Returns the name of the network joined.
+pub fn connect(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    networks: &[KnownNetwork],
+    watchdog: &Watchdog,
+) -> Result<String> {
+    if networks.is_empty() {
+        bail!("No WiFi networks configured")
+    }
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
 
     info!("Scanning...");
 
     let ap_infos = wifi.scan()?;
+    let seen: Vec<Seen> = ap_infos
+        .iter()
+        .map(|ap| Seen {
+            ssid: ap.ssid.as_str(),
+            bssid: ap.bssid,
+            rssi: ap.signal_strength,
+            channel: ap.channel,
+        })
+        .collect();
 
-    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
+    for candidate in roaming::candidates(networks, &seen) {
+        // Each attempt can take a while, trying them all could outlast the watchdog
+        watchdog.feed();
+        let network = candidate.network;
+        match candidate.channel {
+            Some(channel) => info!(
+                "Found known access point {} on channel {}",
+                network.ssid, channel
+            ),
+            None => info!(
+                "Known access point {} not found during scanning, will go with unknown channel",
+                network.ssid
+            ),
+        }
+        match join(&mut wifi, network, candidate.channel) {
+            Ok(()) => return Ok(network.ssid.clone()),
+            Err(e) => {
+                error!("Cannot join {}: {}", network.ssid, e);
+                let _ = wifi.disconnect();
+            }
+        }
+    }
 
-    let channel = if let Some(ours) = ours {
-        info!(
-            "Found configured access point {} on channel {}",
-            ssid, ours.channel
-        );
-        Some(ours.channel)
-    } else {
-        info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
-        );
-        None
+    bail!("No known WiFi network could be joined")
+}
+
+fn join(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    network: &KnownNetwork,
+    channel: Option<u8>,
+) -> Result<()> {
+    network.validate()?;
+
+    let auth_method = match network.security {
+        Security::Auto if network.psk.is_empty() => AuthMethod::None,
+        // The auth method is the weakest accepted, WPA3 access points still work
+        Security::Auto | Security::Wpa2 => AuthMethod::WPA2Personal,
+        Security::Wpa3 => AuthMethod::WPA3Personal,
+        Security::Wpa2Wpa3 => AuthMethod::WPA2WPA3Personal,
+        Security::Open => AuthMethod::None,
+        Security::Enterprise => AuthMethod::WPA2Enterprise,
+    };
+    if auth_method == AuthMethod::None {
+        info!("Wifi password is empty");
+    }
+    match &network.enterprise {
+        Some(login) if network.security == Security::Enterprise => enable_enterprise(login)?,
+        _ => unsafe {
+            esp!(esp_wifi_sta_enterprise_disable())?;
+        },
+    }
+
+    let ssid = match network.ssid.as_str().try_into() {
+        Ok(ssid) => ssid,
+        Err(_) => bail!("SSID too long"),
+    };
+    let password = match network.psk.as_str().try_into() {
+        Ok(password) => password,
+        Err(_) => bail!("Password too long"),
+    };
+    // WPA3 needs protected management frames, mixed networks make them optional
+    let pmf_cfg = match network.security {
+        Security::Wpa3 => PmfConfiguration::Capable { required: true },
+        Security::Wpa2Wpa3 => PmfConfiguration::Capable { required: false },
+        _ => PmfConfiguration::NotCapable,
     };
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid
-            .try_into()
-            .expect("Could not parse the given SSID into WiFi config"),
-        password: pass
-            .try_into()
-            .expect("Could not parse the given password into WiFi config"),
+        ssid,
+        bssid: network.bssid.map(|bssid| bssid.0),
+        password,
         channel,
         auth_method,
+        pmf_cfg,
         ..Default::default()
     }))?;
 
@@ -71,5 +192,71 @@ pub fn wifi(
 
     wifi.connect()?;
 
-    Ok(Box::new(esp_wifi))
+    info!("Waiting for IP address...");
+
+    wifi.wait_netif_up()?;
+
+    Ok(())
+}
+
+fn enable_enterprise(login: &Enterprise) -> Result<()> {
+    // Servers commonly accept an anonymous outer identity
+    let identity = if login.identity.is_empty() {
+        "anonymous"
+    } else {
+        &login.identity
+    };
+
+    unsafe {
+        esp!(esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as _
+        ))?;
+        esp!(esp_eap_client_set_username(
+            login.username.as_ptr(),
+            login.username.len() as _
+        ))?;
+        esp!(esp_eap_client_set_password(
+            login.password.as_ptr(),
+            login.password.len() as _
+        ))?;
+        match &login.ca_cert {
+            Some(pem) => {
+                let pem = ca_cert(pem);
+                esp!(esp_eap_client_set_ca_cert(pem.as_ptr(), pem.len() as _))?;
+            }
+            None => {
+                warn!("No CA for the enterprise network, the server is not verified");
+                esp_eap_client_clear_ca_cert();
+            }
+        }
+        if login.method == EapMethod::Ttls {
+            esp!(esp_eap_client_set_ttls_phase2_method(
+                esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAPV2
+            ))?;
+        }
+        esp!(esp_wifi_sta_enterprise_enable())?;
+    }
+
+    Ok(())
+}
+
+/// The supplicant keeps a pointer to the CA rather than a copy, so it has to
+/// live forever. Every CA is copied once, roaming between enterprise networks
+/// reuses the earlier copies.
+fn ca_cert(pem: &str) -> &'static [u8] {
+    static CA_CERTS: Mutex<Vec<&'static [u8]>> = Mutex::new(Vec::new());
+
+    let mut cached = CA_CERTS.lock().unwrap();
+    // mbedtls wants PEM with its terminating NUL counted in the length
+    let mut bytes = pem.as_bytes().to_vec();
+    bytes.push(0);
+    match cached.iter().find(|ca| **ca == bytes.as_slice()) {
+        Some(ca) => *ca,
+        None => {
+            let ca: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+            cached.push(ca);
+            ca
+        }
+    }
 }